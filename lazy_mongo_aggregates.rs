@@ -3,10 +3,11 @@ use mongodb::{
     bson::{doc, Document, Bson},
     Collection, Database,
     error::Result,
-    options::{UpdateOptions, FindOptions, AggregateOptions},
+    options::{UpdateOptions, FindOptions, AggregateOptions, FindOneAndUpdateOptions, ReturnDocument, Hint},
 };
-use futures::TryStreamExt;
-use serde::{de::DeserializeOwned, Serialize};
+use futures::{future, Stream, TryStreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::mongo_query_filter::struct_to_filter;
 
 #[derive(Debug, Clone)]
 enum PipelineStage {
@@ -15,9 +16,15 @@ enum PipelineStage {
     Sort(Document),
     Limit(i64),
     Skip(i64),
+    Sample(i64),
     Project(Document),
     Lookup(LookupStage),
     Unwind(String),
+    UnwindOpts {
+        path: String,
+        preserve_null_and_empty: bool,
+        include_array_index: Option<String>,
+    },
     Count(String),
     AddFields(Document),
     ReplaceRoot(Document),
@@ -25,7 +32,20 @@ enum PipelineStage {
     Bucket(Document),
     SortByCount(Bson),
     GraphLookup(Document),
-    Out(String),
+    Out {
+        db: Option<String>,
+        coll: String,
+    },
+    Merge {
+        into: String,
+        on: Vec<String>,
+        when_matched: String,
+        when_not_matched: String,
+    },
+    Search(Document),
+    SearchMeta(Document),
+    GeoNear(Document),
+    SetWindowFields(Document),
 }
 
 #[derive(Debug, Clone)]
@@ -42,6 +62,8 @@ enum UpdateOperation {
     Set(Document),
     Unset(Document),
     Pull(Document),
+    PullAll(Document),
+    Pop(Document),
     Push(Document),
     AddToSet(Document),
     Inc(Document),
@@ -52,6 +74,152 @@ enum UpdateOperation {
     CurrentDate(Document),
 }
 
+// $project 构建器：包含、排除与计算字段
+/*
+    let projection = ProjectBuilder::new()
+        .include("email")
+        .exclude("_id")
+        .compute("fullName", Bson::Document(doc! { "$concat": ["$first", " ", "$last"] }));
+
+    builder.project_with(projection)?.execute_aggregate::<User>().await?;
+ */
+#[derive(Debug, Clone, Default)]
+pub struct ProjectBuilder {
+    fields: Document,
+}
+
+impl ProjectBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn include(mut self, field: impl Into<String>) -> Self {
+        self.fields.insert(field.into(), 1);
+        self
+    }
+
+    pub fn exclude(mut self, field: impl Into<String>) -> Self {
+        self.fields.insert(field.into(), 0);
+        self
+    }
+
+    pub fn compute(mut self, name: impl Into<String>, expr: Bson) -> Self {
+        self.fields.insert(name.into(), expr);
+        self
+    }
+
+    fn is_exclusion(value: &Bson) -> bool {
+        match value {
+            Bson::Int32(0) | Bson::Int64(0) | Bson::Boolean(false) => true,
+            Bson::Double(d) => *d == 0.0,
+            _ => false,
+        }
+    }
+
+    // 除 _id 外，包含字段（含计算字段）与排除字段不能混用
+    pub fn build(&self) -> Result<Document> {
+        let mut included: Option<&str> = None;
+        let mut excluded: Option<&str> = None;
+        for (k, v) in self.fields.iter() {
+            if k == "_id" {
+                continue;
+            }
+            if Self::is_exclusion(v) {
+                excluded.get_or_insert(k.as_str());
+            } else {
+                included.get_or_insert(k.as_str());
+            }
+        }
+        if let (Some(inc), Some(exc)) = (included, excluded) {
+            return Err(mongodb::error::Error::custom(format!(
+                "$project cannot mix inclusion of '{}' with exclusion of '{}'", inc, exc
+            )));
+        }
+        Ok(self.fields.clone())
+    }
+}
+
+// $addFields 构建器：逐个添加计算字段，结果与 add_fields(doc! {...}) 完全一致
+/*
+    let fields = AddFieldsBuilder::new()
+        .field("score", Bson::Document(doc! { "$multiply": ["$a", "$b"] }))
+        .field("year", Bson::Document(doc! { "$year": "$created_at" }));
+
+    builder.add_computed(fields).execute_aggregate::<Report>().await?;
+ */
+#[derive(Debug, Clone, Default)]
+pub struct AddFieldsBuilder {
+    fields: Document,
+}
+
+impl AddFieldsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn field(mut self, name: impl Into<String>, expr: Bson) -> Self {
+        self.fields.insert(name.into(), expr);
+        self
+    }
+
+    pub fn build(&self) -> Document {
+        self.fields.clone()
+    }
+}
+
+// group_by_time 的时间粒度
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeUnit {
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+impl TimeUnit {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TimeUnit::Minute => "minute",
+            TimeUnit::Hour => "hour",
+            TimeUnit::Day => "day",
+            TimeUnit::Week => "week",
+            TimeUnit::Month => "month",
+        }
+    }
+}
+
+// 聚合结果反序列化失败时的处理策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeserializeErrorPolicy {
+    #[default]
+    Fail,
+    Skip,
+    Collect,
+}
+
+// $count / $sum 等返回的数值可能是 Int32、Int64 或 Double
+fn bson_to_u64(value: &Bson) -> u64 {
+    match value {
+        Bson::Int32(i) => *i as u64,
+        Bson::Int64(i) => *i as u64,
+        Bson::Double(d) => *d as u64,
+        _ => 0,
+    }
+}
+
+// summarize() 的返回结果，空集合时 count 为 0、其余为默认值
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct FieldSummary {
+    #[serde(default)]
+    pub sum: f64,
+    pub avg: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    #[serde(default)]
+    pub count: u64,
+}
+
 pub struct MongoAggregateBuilder<'a, T> 
 where 
     T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static
@@ -65,6 +233,7 @@ where
     find_options: Option<FindOptions>,
     aggregate_options: Option<AggregateOptions>,
     is_upsert: bool,
+    deserialize_policy: DeserializeErrorPolicy,
 }
 
 impl<'a, T> MongoAggregateBuilder<'a, T> 
@@ -82,6 +251,7 @@ where
             find_options: None,
             aggregate_options: None,
             is_upsert: false,
+            deserialize_policy: DeserializeErrorPolicy::Fail,
         }
     }
 
@@ -99,7 +269,7 @@ where
         builder
         .filter(doc! { "type": "order" })  // 第一个查询条件
         .lookup("users", "user_id", "_id", "user_info")  // 关联查询
-        .match(doc! { "status": "active" })  // 第二个查询条件
+        .match_stage(doc! { "user_info.status": "active" })  // 第二个查询条件
         .project(doc! { "id": 1, "user_info": 1 })
         .execute_aggregate()
     
@@ -109,11 +279,57 @@ where
         self
     }
 
+    // 在当前位置加入 $match，可以放在 $lookup/$unwind 之后过滤关联结果；filter 始终在管道最前面
+    pub fn match_stage(mut self, doc: Document) -> Self {
+        self.pipeline_stages.push(PipelineStage::Match(doc));
+        self
+    }
+
+    /*
+        #[derive(Serialize)]
+        struct OrderQuery { status: Option<String>, user_id: Option<i64> }
+        builder.filter_struct(&OrderQuery { status: Some("paid".into()), user_id: None })?
+        // 等价于 .filter(doc! { "status": "paid" })
+     */
+    pub fn filter_struct<Q: Serialize>(self, query: &Q) -> Result<Self> {
+        let filter = struct_to_filter(query)?;
+        Ok(self.filter(filter))
+    }
+
     pub fn upsert(mut self, upsert: bool) -> Self {
         self.is_upsert = upsert;
         self
     }
 
+    // 强制聚合使用指定索引（作用于开头的 $match），可以配合 explain 确认执行计划
+    pub fn hint_index(mut self, name: &str) -> Self {
+        self.aggregate_options
+            .get_or_insert_with(AggregateOptions::default)
+            .hint = Some(Hint::Name(name.to_string()));
+        self
+    }
+
+    // 大的 $group / $sort 超过 100MB 内存限制时允许写临时文件
+    pub fn allow_disk_use(mut self, allow: bool) -> Self {
+        self.aggregate_options
+            .get_or_insert_with(AggregateOptions::default)
+            .allow_disk_use = Some(allow);
+        self
+    }
+
+    // 游标每批返回的文档数
+    pub fn batch_size(mut self, size: u32) -> Self {
+        self.aggregate_options
+            .get_or_insert_with(AggregateOptions::default)
+            .batch_size = Some(size);
+        self
+    }
+
+    pub fn on_deserialize_error(mut self, policy: DeserializeErrorPolicy) -> Self {
+        self.deserialize_policy = policy;
+        self
+    }
+
     // 聚合管道构建方法
     pub fn lookup(mut self, from: impl Into<String>, local_field: impl Into<String>, 
                  foreign_field: impl Into<String>, as_field: impl Into<String>) -> Self {
@@ -140,31 +356,202 @@ where
         self
     }
 
+    /*
+        一对一关联并展开：$lookup 之后 $unwind（preserveNullAndEmptyArrays: true），
+        as_field 变成单个子文档，没有匹配的父文档依然保留（不含 as_field）
+        builder.lookup_one("users", "user_id", "_id", "user")
+        // 输出: { _id: ..., user_id: ..., user: { _id: ..., name: ... } }
+     */
+    pub fn lookup_one(self, from: impl Into<String>, local_field: impl Into<String>,
+                      foreign_field: impl Into<String>, as_field: impl Into<String>) -> Self {
+        let as_field = as_field.into();
+        let mut builder = self.lookup(from, local_field, foreign_field, as_field.clone());
+        builder.pipeline_stages.push(PipelineStage::UnwindOpts {
+            path: as_field,
+            preserve_null_and_empty: true,
+            include_array_index: None,
+        });
+        builder
+    }
+
     pub fn unwind(mut self, path: impl Into<String>) -> Self {
         self.pipeline_stages.push(PipelineStage::Unwind(path.into()));
         self
     }
 
+    /*
+        文档形式的 $unwind，保留空数组/缺失字段的文档，并可记录元素下标：
+        builder.unwind_opts("items", true, Some("item_index"))
+        => { $unwind: { path: "$items", preserveNullAndEmptyArrays: true, includeArrayIndex: "item_index" } }
+     */
+    pub fn unwind_opts(mut self, path: impl Into<String>, preserve_null_and_empty: bool, include_array_index: Option<&str>) -> Self {
+        self.pipeline_stages.push(PipelineStage::UnwindOpts {
+            path: path.into(),
+            preserve_null_and_empty,
+            include_array_index: include_array_index.map(str::to_string),
+        });
+        self
+    }
+
     pub fn group(mut self, group: Document) -> Self {
         self.pipeline_stages.push(PipelineStage::Group(group));
         self
     }
 
+    /*
+        向紧邻的 $group 中追加数组累加器，前一个阶段不是 $group 时按 _id: null 整体分组
+        builder.group(doc! { "_id": "$customer_id" })
+            .push_into("order_ids", "$_id")
+            .add_to_set_into("products", "$product_id")
+        // 输出: { _id: "c1", order_ids: [...], products: [...] }，可直接反序列化到 Vec<_> 字段
+     */
+    pub fn push_into(self, name: &str, expr: impl Into<Bson>) -> Self {
+        self.merge_group(name, doc! { "$push": expr.into() })
+    }
+
+    pub fn add_to_set_into(self, name: &str, expr: impl Into<Bson>) -> Self {
+        self.merge_group(name, doc! { "$addToSet": expr.into() })
+    }
+
+    fn merge_group(mut self, name: &str, accumulator: Document) -> Self {
+        if let Some(PipelineStage::Group(group)) = self.pipeline_stages.last_mut() {
+            group.insert(name, accumulator);
+            return self;
+        }
+        self.group(doc! { "_id": Bson::Null, name: accumulator })
+    }
+
+    /*
+        按时间桶分组（$dateTrunc，MongoDB 5.0+），filter 仍然在最前面执行
+        builder.filter(doc! { "type": "click" })
+            .group_by_time("created_at", TimeUnit::Hour, doc! { "clicks": { "$sum": 1 } })
+            .sort(doc! { "_id": 1 })
+            .execute_aggregate::<HourlyClicks>()
+            .await?;
+        // 输出: { _id: ISODate("2024-01-01T10:00:00Z"), clicks: 42 }
+     */
+    pub fn group_by_time(self, date_field: &str, unit: TimeUnit, accumulators: Document) -> Self {
+        let mut group = doc! {
+            "_id": {
+                "$dateTrunc": { "date": format!("${}", date_field), "unit": unit.as_str() }
+            }
+        };
+        for (k, v) in accumulators {
+            group.insert(k, v);
+        }
+        self.group(group)
+    }
+
     pub fn sort(mut self, sort: Document) -> Self {
         self.pipeline_stages.push(PipelineStage::Sort(sort));
         self
     }
 
+    pub fn limit(mut self, n: i64) -> Self {
+        self.pipeline_stages.push(PipelineStage::Limit(n));
+        self
+    }
+
+    pub fn skip(mut self, n: i64) -> Self {
+        self.pipeline_stages.push(PipelineStage::Skip(n));
+        self
+    }
+
+    // 随机抽取 size 条文档，size 必须大于 0
+    pub fn sample(mut self, size: i64) -> Result<Self> {
+        if size <= 0 {
+            return Err(mongodb::error::Error::custom(format!(
+                "$sample size must be positive, got {}", size
+            )));
+        }
+        self.pipeline_stages.push(PipelineStage::Sample(size));
+        Ok(self)
+    }
+
     pub fn project(mut self, project: Document) -> Self {
         self.pipeline_stages.push(PipelineStage::Project(project));
         self
     }
 
+    /*
+        只返回数组的一部分，聚合中的 $slice 是表达式：{ comments: { $slice: ["$comments", 5] } }
+        紧跟在另一个包含/计算型 $project 之后时合并进同一个阶段，否则用 $addFields 覆盖该字段，其它字段保留
+        （单独的 $project 会把未列出的字段全部去掉）
+        builder.project(doc! { "title": 1 }).project_slice("comments", -5)
+     */
+    pub fn project_slice(self, field: &str, n: i32) -> Self {
+        let path = format!("${}", field);
+        self.merge_project(field, doc! { "$slice": [path, n] })
+    }
+
+    // 跳过 skip 个元素后取 limit 个
+    pub fn project_slice_range(self, field: &str, skip: i32, limit: i32) -> Self {
+        let path = format!("${}", field);
+        self.merge_project(field, doc! { "$slice": [path, skip, limit] })
+    }
+
+    fn merge_project(mut self, field: &str, value: Document) -> Self {
+        // 只有包含型 $project 才能合并，排除型 $project 中不能出现表达式（_id: 0 除外）
+        if let Some(PipelineStage::Project(project)) = self.pipeline_stages.last_mut() {
+            if project.iter().any(|(k, v)| k != "_id" && !ProjectBuilder::is_exclusion(v)) {
+                project.insert(field, value);
+                return self;
+            }
+        }
+        self.add_fields(doc! { field: value })
+    }
+
+    pub fn project_with(self, projection: ProjectBuilder) -> Result<Self> {
+        let project = projection.build()?;
+        Ok(self.project(project))
+    }
+
+    /*
+        重命名输出字段，未列出的字段保留：
+        { $addFields: { userName: "$name" } }, { $project: { name: 0 } }
+        builder.rename_output(&[("name", "userName"), ("created_at", "createdAt")])
+     */
+    pub fn rename_output(self, renames: &[(&str, &str)]) -> Self {
+        if renames.is_empty() {
+            return self;
+        }
+        let mut fields = Document::new();
+        for (old, new) in renames {
+            fields.insert(*new, format!("${}", old));
+        }
+        // 旧字段名同时又是新字段名时（如交换两个字段）不能排除
+        let mut excluded = Document::new();
+        for (old, _) in renames {
+            if !renames.iter().any(|(_, new)| new == old) {
+                excluded.insert(*old, 0);
+            }
+        }
+        let builder = self.add_fields(fields);
+        if excluded.is_empty() {
+            builder
+        } else {
+            builder.project(excluded)
+        }
+    }
+
+    // 只输出重命名后的字段，其余字段（包括 _id）都丢弃
+    pub fn rename_output_only(self, renames: &[(&str, &str)]) -> Self {
+        let mut project = doc! { "_id": 0 };
+        for (old, new) in renames {
+            project.insert(*new, format!("${}", old));
+        }
+        self.project(project)
+    }
+
     pub fn add_fields(mut self, fields: Document) -> Self {
         self.pipeline_stages.push(PipelineStage::AddFields(fields));
         self
     }
 
+    pub fn add_computed(self, fields: AddFieldsBuilder) -> Self {
+        self.add_fields(fields.build())
+    }
+
     pub fn replace_root(mut self, new_root: Document) -> Self {
         self.pipeline_stages.push(PipelineStage::ReplaceRoot(new_root));
         self
@@ -199,6 +586,153 @@ where
         self
     }
 
+    /*
+        Atlas Search：$search/$searchMeta 必须是第一个阶段，不能与 filter 同时使用（validate 会报错），
+        过滤条件请写在 compound.filter 中
+        builder.search(doc! {
+                "index": "default",
+                "text": { "query": "mongodb", "path": ["title", "body"] }
+            })
+            .project(doc! { "title": 1, "score": { "$meta": "searchScore" } })
+            .execute_aggregate::<Article>()
+            .await?;
+     */
+    pub fn search(mut self, search: Document) -> Self {
+        self.pipeline_stages.push(PipelineStage::Search(search));
+        self
+    }
+
+    pub fn search_meta(mut self, search_meta: Document) -> Self {
+        self.pipeline_stages.push(PipelineStage::SearchMeta(search_meta));
+        self
+    }
+
+    /*
+        按距离由近到远返回文档，需要 2dsphere / 2d 索引；$geoNear 必须是第一个阶段，
+        作为第一个添加的阶段时会放在 filter 生成的 $match 之前，其他位置由 validate 报错
+        builder.geo_near(
+                Bson::Document(doc! { "type": "Point", "coordinates": [116.4, 39.9] }),
+                "distance", true, Some(5000.0), Some(doc! { "category": "cafe" }),
+            )
+            .limit(20)
+            .execute_aggregate::<Shop>()
+            .await?;
+     */
+    pub fn geo_near(mut self, near: Bson, distance_field: &str, spherical: bool, max_distance: Option<f64>, query: Option<Document>) -> Self {
+        let mut geo_near = doc! {
+            "near": near,
+            "distanceField": distance_field,
+            "spherical": spherical,
+        };
+        if let Some(max_distance) = max_distance {
+            geo_near.insert("maxDistance", max_distance);
+        }
+        if let Some(query) = query {
+            geo_near.insert("query", query);
+        }
+        self.pipeline_stages.push(PipelineStage::GeoNear(geo_near));
+        self
+    }
+
+    /*
+        窗口函数：累计求和、移动平均等
+        builder.set_window_fields(
+            Some(Bson::String("$user_id".to_string())),
+            Some(doc! { "created_at": 1 }),
+            doc! { "running_total": {
+                "$sum": "$amount",
+                "window": { "documents": ["unbounded", "current"] }
+            } },
+        )
+     */
+    pub fn set_window_fields(mut self, partition_by: Option<Bson>, sort_by: Option<Document>, output: Document) -> Self {
+        let mut window = Document::new();
+        if let Some(partition_by) = partition_by {
+            window.insert("partitionBy", partition_by);
+        }
+        if let Some(sort_by) = sort_by {
+            window.insert("sortBy", sort_by);
+        }
+        window.insert("output", output);
+        self.pipeline_stages.push(PipelineStage::SetWindowFields(window));
+        self
+    }
+
+    // 输出匹配的文档数量：{ field: n }
+    pub fn count(mut self, field: impl Into<String>) -> Self {
+        self.pipeline_stages.push(PipelineStage::Count(field.into()));
+        self
+    }
+
+    /*
+        按边界分桶
+        builder.bucket(doc! {
+            "groupBy": "$price",
+            "boundaries": [0, 100, 500],
+            "default": "other",
+            "output": { "count": { "$sum": 1 } }
+        })
+     */
+    pub fn bucket(mut self, bucket: Document) -> Self {
+        self.pipeline_stages.push(PipelineStage::Bucket(bucket));
+        self
+    }
+
+    // 按表达式分组计数并按数量降序：sort_by_count("$category")
+    pub fn sort_by_count(mut self, expression: impl Into<Bson>) -> Self {
+        self.pipeline_stages.push(PipelineStage::SortByCount(expression.into()));
+        self
+    }
+
+    /*
+        递归关联，如组织架构的上下级
+        builder.graph_lookup(doc! {
+            "from": "employees",
+            "startWith": "$manager_id",
+            "connectFromField": "manager_id",
+            "connectToField": "_id",
+            "as": "managers"
+        })
+     */
+    pub fn graph_lookup(mut self, graph_lookup: Document) -> Self {
+        self.pipeline_stages.push(PipelineStage::GraphLookup(graph_lookup));
+        self
+    }
+
+    // 将聚合结果写入当前数据库的集合（覆盖目标集合），必须是最后一个阶段
+    pub fn out(mut self, coll: impl Into<String>) -> Self {
+        self.pipeline_stages.push(PipelineStage::Out {
+            db: None,
+            coll: coll.into(),
+        });
+        self
+    }
+
+    // 将聚合结果写入另一个数据库的集合，如报表库
+    pub fn out_to(mut self, db: impl Into<String>, coll: impl Into<String>) -> Self {
+        self.pipeline_stages.push(PipelineStage::Out {
+            db: Some(db.into()),
+            coll: coll.into(),
+        });
+        self
+    }
+
+    /*
+        增量写入目标集合（物化视图），按 on 字段匹配，必须是最后一个阶段；on 为空时按 _id 匹配
+        builder.group(doc! { "_id": "$user_id", "total": { "$sum": "$amount" } })
+            .merge_into("user_totals", vec!["_id".to_string()], "replace", "insert")
+        => { $merge: { into: "user_totals", on: ["_id"], whenMatched: "replace", whenNotMatched: "insert" } }
+     */
+    pub fn merge_into(mut self, into: &str, on: Vec<String>, when_matched: &str, when_not_matched: &str) -> Self {
+        self.pipeline_stages.push(PipelineStage::Merge {
+            into: into.to_string(),
+            on,
+            when_matched: when_matched.to_string(),
+            when_not_matched: when_not_matched.to_string(),
+        });
+        self
+    }
+
     // 更新操作构建方法
     pub fn set(mut self, update: Document) -> Self {
         self.update_operations.push(UpdateOperation::Set(update));
@@ -215,6 +749,17 @@ where
         self
     }
 
+    pub fn pull_all(mut self, pull_all: Document) -> Self {
+        self.update_operations.push(UpdateOperation::PullAll(pull_all));
+        self
+    }
+
+    // 1 移除最后一个元素，-1 移除第一个元素
+    pub fn pop(mut self, pop: Document) -> Self {
+        self.update_operations.push(UpdateOperation::Pop(pop));
+        self
+    }
+
     pub fn push(mut self, push: Document) -> Self {
         self.update_operations.push(UpdateOperation::Push(push));
         self
@@ -230,23 +775,61 @@ where
         self
     }
 
+    pub fn max(mut self, max: Document) -> Self {
+        self.update_operations.push(UpdateOperation::Max(max));
+        self
+    }
+
+    pub fn min(mut self, min: Document) -> Self {
+        self.update_operations.push(UpdateOperation::Min(min));
+        self
+    }
+
+    pub fn mul(mut self, mul: Document) -> Self {
+        self.update_operations.push(UpdateOperation::Mul(mul));
+        self
+    }
+
+    // { 旧字段名: "新字段名" }
+    pub fn rename(mut self, rename: Document) -> Self {
+        self.update_operations.push(UpdateOperation::Rename(rename));
+        self
+    }
+
+    // { updated_at: true } 或 { updated_at: { "$type": "timestamp" } }
+    pub fn current_date(mut self, current_date: Document) -> Self {
+        self.update_operations.push(UpdateOperation::CurrentDate(current_date));
+        self
+    }
+
     // 构建更新文档
+    // 同一个操作符多次调用时合并字段，同名字段后面的覆盖前面的：.set(a).set(b) => { $set: { ...a, ...b } }
     fn build_update_doc(&self) -> Document {
         let mut update = Document::new();
         
         for op in &self.update_operations {
-            match op {
-                UpdateOperation::Set(doc) => { update.insert("$set", doc); }
-                UpdateOperation::Unset(doc) => { update.insert("$unset", doc); }
-                UpdateOperation::Pull(doc) => { update.insert("$pull", doc); }
-                UpdateOperation::Push(doc) => { update.insert("$push", doc); }
-                UpdateOperation::AddToSet(doc) => { update.insert("$addToSet", doc); }
-                UpdateOperation::Inc(doc) => { update.insert("$inc", doc); }
-                UpdateOperation::Max(doc) => { update.insert("$max", doc); }
-                UpdateOperation::Min(doc) => { update.insert("$min", doc); }
-                UpdateOperation::Mul(doc) => { update.insert("$mul", doc); }
-                UpdateOperation::Rename(doc) => { update.insert("$rename", doc); }
-                UpdateOperation::CurrentDate(doc) => { update.insert("$currentDate", doc); }
+            let (operator, fields) = match op {
+                UpdateOperation::Set(doc) => ("$set", doc),
+                UpdateOperation::Unset(doc) => ("$unset", doc),
+                UpdateOperation::Pull(doc) => ("$pull", doc),
+                UpdateOperation::PullAll(doc) => ("$pullAll", doc),
+                UpdateOperation::Pop(doc) => ("$pop", doc),
+                UpdateOperation::Push(doc) => ("$push", doc),
+                UpdateOperation::AddToSet(doc) => ("$addToSet", doc),
+                UpdateOperation::Inc(doc) => ("$inc", doc),
+                UpdateOperation::Max(doc) => ("$max", doc),
+                UpdateOperation::Min(doc) => ("$min", doc),
+                UpdateOperation::Mul(doc) => ("$mul", doc),
+                UpdateOperation::Rename(doc) => ("$rename", doc),
+                UpdateOperation::CurrentDate(doc) => ("$currentDate", doc),
+            };
+            match update.get_mut(operator) {
+                Some(Bson::Document(existing)) => {
+                    for (k, v) in fields {
+                        existing.insert(k.clone(), v.clone());
+                    }
+                }
+                _ => { update.insert(operator, fields.clone()); }
             }
         }
         update
@@ -255,12 +838,19 @@ where
     // 构建聚合管道
     fn build_pipeline(&self) -> Vec<Document> {
         let mut pipeline = Vec::new();
-        
+
+        // 第一个阶段是 $geoNear 时放在 filter 之前
+        let mut stages = self.pipeline_stages.as_slice();
+        if let Some((PipelineStage::GeoNear(doc), rest)) = stages.split_first() {
+            pipeline.push(doc! { "$geoNear": doc });
+            stages = rest;
+        }
+
         if let Some(filter) = &self.filter {
             pipeline.push(doc! { "$match": filter });
         }
 
-        for ref stage in &self.pipeline_stages {
+        for ref stage in stages {
             match stage {
                 PipelineStage::Match(doc) => { 
                     pipeline.push(doc! { "$match": doc }); 
@@ -277,6 +867,9 @@ where
                 PipelineStage::Skip(skip) => {
                     pipeline.push(doc! { "$skip": skip });
                 }
+                PipelineStage::Sample(size) => {
+                    pipeline.push(doc! { "$sample": { "size": size } });
+                }
                 PipelineStage::Project(doc) => { 
                     pipeline.push(doc! { "$project": doc }); 
                 }
@@ -295,6 +888,16 @@ where
                 PipelineStage::Unwind(path) => {
                     pipeline.push(doc! { "$unwind": format!("${}", path) });
                 }
+                PipelineStage::UnwindOpts { path, preserve_null_and_empty, include_array_index } => {
+                    let mut unwind_doc = doc! {
+                        "path": format!("${}", path),
+                        "preserveNullAndEmptyArrays": *preserve_null_and_empty,
+                    };
+                    if let Some(index_field) = include_array_index {
+                        unwind_doc.insert("includeArrayIndex", index_field);
+                    }
+                    pipeline.push(doc! { "$unwind": unwind_doc });
+                }
                 PipelineStage::AddFields(doc) => {
                     pipeline.push(doc! { "$addFields": doc });
                 }
@@ -316,35 +919,356 @@ where
                 PipelineStage::GraphLookup(doc) => {
                     pipeline.push(doc! { "$graphLookup": doc });
                 }
-                PipelineStage::Out(collection) => {
-                    pipeline.push(doc! { "$out": collection });
+                PipelineStage::Search(doc) => {
+                    pipeline.push(doc! { "$search": doc });
+                }
+                PipelineStage::SearchMeta(doc) => {
+                    pipeline.push(doc! { "$searchMeta": doc });
+                }
+                PipelineStage::GeoNear(doc) => {
+                    pipeline.push(doc! { "$geoNear": doc });
+                }
+                PipelineStage::SetWindowFields(doc) => {
+                    pipeline.push(doc! { "$setWindowFields": doc });
+                }
+                PipelineStage::Out { db, coll } => {
+                    match db {
+                        Some(db) => pipeline.push(doc! { "$out": { "db": db, "coll": coll } }),
+                        None => pipeline.push(doc! { "$out": coll }),
+                    }
+                }
+                PipelineStage::Merge { into, on, when_matched, when_not_matched } => {
+                    let mut merge_doc = doc! { "into": into };
+                    if !on.is_empty() {
+                        merge_doc.insert("on", on.clone());
+                    }
+                    merge_doc.insert("whenMatched", when_matched);
+                    merge_doc.insert("whenNotMatched", when_not_matched);
+                    pipeline.push(doc! { "$merge": merge_doc });
                 }
             }
         }
         pipeline
     }
 
+    // 检查阶段的位置约束：$geoNear/$indexStats/$collStats/$search 必须在第一位，$out/$merge 必须在最后
+    pub fn validate(&self) -> Result<()> {
+        Self::validate_pipeline(&self.build_pipeline())
+    }
+
+    fn validate_pipeline(pipeline: &[Document]) -> Result<()> {
+        const FIRST_ONLY: [&str; 5] = ["$geoNear", "$indexStats", "$collStats", "$search", "$searchMeta"];
+        const LAST_ONLY: [&str; 2] = ["$out", "$merge"];
+
+        let last = pipeline.len().saturating_sub(1);
+        for (position, stage) in pipeline.iter().enumerate() {
+            let name = match stage.keys().next() {
+                Some(name) => name.as_str(),
+                None => continue,
+            };
+            if FIRST_ONLY.contains(&name) && position != 0 {
+                return Err(mongodb::error::Error::custom(format!(
+                    "{} must be the first stage, found at position {}", name, position
+                )));
+            }
+            if LAST_ONLY.contains(&name) && position != last {
+                return Err(mongodb::error::Error::custom(format!(
+                    "{} must be the last stage, found at position {} of {}", name, position, pipeline.len()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // explain 命令与实际执行使用同一个 build_pipeline，并带上 hint / allowDiskUse
+    fn explain_command(&self) -> Result<Document> {
+        let pipeline = self.build_pipeline();
+        Self::validate_pipeline(&pipeline)?;
+        let mut aggregate = doc! {
+            "aggregate": self.collection.name(),
+            "pipeline": pipeline,
+            "cursor": {},
+        };
+        if let Some(options) = &self.aggregate_options {
+            match &options.hint {
+                Some(Hint::Name(name)) => { aggregate.insert("hint", name.as_str()); }
+                Some(Hint::Keys(keys)) => { aggregate.insert("hint", keys.clone()); }
+                _ => {}
+            }
+            if let Some(allow) = options.allow_disk_use {
+                aggregate.insert("allowDiskUse", allow);
+            }
+        }
+        Ok(doc! { "explain": aggregate, "verbosity": "queryPlanner" })
+    }
+
+    /*
+        返回执行计划而不真正跑完整个聚合，用于排查慢查询：
+        let plan = builder.filter(doc! { "status": "paid" }).hint_index("status_1").explain().await?;
+        println!("{}", plan.get_document("queryPlanner")?);
+     */
+    pub async fn explain(&self) -> Result<Document> {
+        let command = self.explain_command()?;
+        self.database.run_command(command).await
+    }
+
     // 执行方法
     pub async fn execute_aggregate<U: DeserializeOwned>(&self) -> Result<Vec<U>> {
+        let (results, _) = self.execute_aggregate_collect().await?;
+        Ok(results)
+    }
+
+    /*
+        逐条返回反序列化后的文档，不把整个结果集读进内存
+        deserialize_policy 为 Skip / Collect 时跳过无法解析的文档（流式下不收集失败项）
+        let mut orders = builder.execute_aggregate_stream::<Order>().await?;
+        while let Some(order) = orders.try_next().await? {
+            ...
+        }
+     */
+    pub async fn execute_aggregate_stream<U: DeserializeOwned>(&self) -> Result<impl Stream<Item = Result<U>> + Unpin> {
+        let pipeline = self.build_pipeline();
+        Self::validate_pipeline(&pipeline)?;
+        let cursor = self.collection.aggregate(pipeline).with_options(self.aggregate_options.clone()).await?;
+
+        let policy = self.deserialize_policy;
+        Ok(cursor.try_filter_map(move |result| {
+            future::ready(match mongodb::bson::from_document(result) {
+                Ok(value) => Ok(Some(value)),
+                Err(e) => match policy {
+                    DeserializeErrorPolicy::Fail => Err(e.into()),
+                    DeserializeErrorPolicy::Skip | DeserializeErrorPolicy::Collect => Ok(None),
+                },
+            })
+        }))
+    }
+
+    /*
+        按 deserialize_policy 处理无法反序列化的文档：
+        Fail: 遇到第一个错误即返回（默认）
+        Skip: 丢弃无法解析的文档
+        Collect: 丢弃并把原始文档和错误一起返回，便于迁移时排查旧数据
+        let (orders, failures) = builder
+            .on_deserialize_error(DeserializeErrorPolicy::Collect)
+            .execute_aggregate_collect::<Order>()
+            .await?;
+     */
+    pub async fn execute_aggregate_collect<U: DeserializeOwned>(&self) -> Result<(Vec<U>, Vec<(Document, mongodb::bson::de::Error)>)> {
         let pipeline = self.build_pipeline();
-        let mut cursor = self.collection.aggregate(pipeline).await?;
+        Self::validate_pipeline(&pipeline)?;
+        let mut cursor = self.collection.aggregate(pipeline).with_options(self.aggregate_options.clone()).await?;
         
         let mut results = Vec::new();
+        let mut failures = Vec::new();
         while let Some(result) = cursor.try_next().await? {
-            results.push(mongodb::bson::from_document(result)?);
+            // 只有 Collect 需要保留原始文档，其他策略不复制
+            let original = (self.deserialize_policy == DeserializeErrorPolicy::Collect).then(|| result.clone());
+            match mongodb::bson::from_document(result) {
+                Ok(value) => results.push(value),
+                Err(e) => match (self.deserialize_policy, original) {
+                    (DeserializeErrorPolicy::Fail, _) => return Err(e.into()),
+                    (DeserializeErrorPolicy::Collect, Some(original)) => failures.push((original, e)),
+                    _ => {}
+                },
+            }
+        }
+        Ok((results, failures))
+    }
+
+    // 在同一个 filter / stages 上分别生成分页和计数两条管道（page 从 1 开始，0 按 1 处理）
+    fn page_pipelines(&self, page: u64, per_page: u64) -> Result<(Vec<Document>, Vec<Document>)> {
+        if per_page == 0 {
+            return Err(mongodb::error::Error::custom("Page size must be greater than 0"));
+        }
+        let skip = page.max(1).saturating_sub(1).saturating_mul(per_page);
+        let base = self.build_pipeline();
+
+        let mut data_pipeline = base.clone();
+        data_pipeline.push(doc! { "$skip": skip as i64 });
+        data_pipeline.push(doc! { "$limit": per_page as i64 });
+
+        let mut count_pipeline = base;
+        count_pipeline.push(doc! { "$count": "total" });
+        Self::validate_pipeline(&data_pipeline)?;
+        Self::validate_pipeline(&count_pipeline)?;
+        Ok((data_pipeline, count_pipeline))
+    }
+
+    /*
+        返回当前页数据和匹配总数，分两次查询，避免 $facet 把整页数据塞进单个 16MB 文档：
+        [ ...stages, { $skip: (page-1)*per_page }, { $limit: per_page } ]
+        [ ...stages, { $count: "total" } ]
+        let (orders, total) = builder.filter(doc! { "status": "paid" })
+            .sort(doc! { "created_at": -1 })
+            .paginate::<Order>(2, 20)
+            .await?;
+     */
+    pub async fn paginate<U: DeserializeOwned>(&self, page: u64, per_page: u64) -> Result<(Vec<U>, u64)> {
+        let (data_pipeline, count_pipeline) = self.page_pipelines(page, per_page)?;
+
+        let mut cursor = self.collection.aggregate(data_pipeline).with_options(self.aggregate_options.clone()).await?;
+        let mut data = Vec::new();
+        while let Some(result) = cursor.try_next().await? {
+            data.push(mongodb::bson::from_document(result)?);
+        }
+
+        let mut cursor = self.collection.aggregate(count_pipeline).with_options(self.aggregate_options.clone()).await?;
+        let total = match cursor.try_next().await? {
+            Some(result) => result.get("total").map(bson_to_u64).unwrap_or(0),
+            None => 0,
+        };
+        Ok((data, total))
+    }
+
+    // 一次 $group 同时计算某个数值字段的 sum/avg/min/max/count，先应用已有的 filter 和 stages
+    pub async fn summarize(&self, field: &str) -> Result<FieldSummary> {
+        let mut pipeline = self.build_pipeline();
+        let path = format!("${}", field);
+        pipeline.push(doc! {
+            "$group": {
+                "_id": Bson::Null,
+                "sum": { "$sum": &path },
+                "avg": { "$avg": &path },
+                "min": { "$min": &path },
+                "max": { "$max": &path },
+                "count": { "$count": {} },
+            }
+        });
+        Self::validate_pipeline(&pipeline)?;
+
+        let mut cursor = self.collection.aggregate(pipeline).with_options(self.aggregate_options.clone()).await?;
+        match cursor.try_next().await? {
+            Some(result) => Ok(mongodb::bson::from_document(result)?),
+            None => Ok(FieldSummary::default()),
+        }
+    }
+
+    // 统计某个字段不同取值的个数，先应用已有的 filter 和 stages，没有匹配文档时返回 0
+    pub async fn count_distinct(&self, field: &str) -> Result<u64> {
+        let mut pipeline = self.build_pipeline();
+        pipeline.push(doc! { "$group": { "_id": format!("${}", field) } });
+        pipeline.push(doc! { "$count": "n" });
+        Self::validate_pipeline(&pipeline)?;
+
+        let mut cursor = self.collection.aggregate(pipeline).with_options(self.aggregate_options.clone()).await?;
+        match cursor.try_next().await? {
+            Some(result) => Ok(result.get("n").map(bson_to_u64).unwrap_or(0)),
+            None => Ok(0),
+        }
+    }
+
+    /*
+        用当前管道流式选出 _id，每攒够 batch 个就执行一次 update_many({ _id: { $in: [...] } }, update)，
+        内存中最多保留 batch 个 _id
+        let modified = builder.filter(doc! { "status": "pending" })
+            .lookup("users", "user_id", "_id", "user")
+            .unwind("user")
+            .update_matching_ids(doc! { "$set": { "status": "blocked" } }, 1000)
+            .await?;
+     */
+    pub async fn update_matching_ids(&self, update: Document, batch: usize) -> Result<u64> {
+        if batch == 0 {
+            return Err(mongodb::error::Error::custom("Batch size must be greater than 0"));
+        }
+        let mut pipeline = self.build_pipeline();
+        pipeline.push(doc! { "$project": { "_id": 1 } });
+        Self::validate_pipeline(&pipeline)?;
+
+        let mut cursor = self.collection.aggregate(pipeline).with_options(self.aggregate_options.clone()).await?;
+        let mut ids = Vec::with_capacity(batch);
+        let mut modified = 0;
+        while let Some(mut result) = cursor.try_next().await? {
+            if let Some(id) = result.remove("_id") {
+                ids.push(id);
+            }
+            if ids.len() == batch {
+                modified += self.update_ids(std::mem::take(&mut ids), &update).await?;
+            }
+        }
+        if !ids.is_empty() {
+            modified += self.update_ids(ids, &update).await?;
+        }
+        Ok(modified)
+    }
+
+    async fn update_ids(&self, ids: Vec<Bson>, update: &Document) -> Result<u64> {
+        let result = self.collection
+            .update_many(doc! { "_id": { "$in": ids } }, update.clone())
+            .await?;
+        Ok(result.modified_count)
+    }
+
+    /*
+        导出用：逐条把结果交给 sink，不在内存中缓存全部结果
+        开启 allowDiskUse，按 batch_size 拉取，不设置 maxTimeMS
+        let mut writer = csv::Writer::from_path("orders.csv")?;
+        let exported = builder.filter(doc! { "year": 2024 })
+            .export_aggregate::<Order, _>(5000, |order| {
+                writer.serialize(order).map_err(|e| mongodb::error::Error::custom(e.to_string()))
+            })
+            .await?;
+     */
+    pub async fn export_aggregate<U, F>(&self, batch_size: u32, mut sink: F) -> Result<u64>
+    where
+        U: DeserializeOwned,
+        F: FnMut(U) -> Result<()>,
+    {
+        let pipeline = self.build_pipeline();
+        Self::validate_pipeline(&pipeline)?;
+        let mut cursor = self.collection
+            .aggregate(pipeline)
+            .with_options(self.aggregate_options.clone())
+            .allow_disk_use(true)
+            .batch_size(batch_size)
+            .await?;
+
+        let mut exported = 0;
+        while let Some(result) = cursor.try_next().await? {
+            sink(mongodb::bson::from_document(result)?)?;
+            exported += 1;
+        }
+        Ok(exported)
+    }
+
+    /*
+        只生成不执行，返回 execute_update 将要发送的 (filter, update)，没有 filter 时与 execute_update 一样报错
+        let (filter, update) = builder.filter(doc! { "_id": id }).set(doc! { "status": "paid" }).preview_update()?;
+     */
+    pub fn preview_update(&self) -> Result<(Document, Document)> {
+        match &self.filter {
+            Some(filter) => Ok((filter.clone(), self.build_update_doc())),
+            None => Err(mongodb::error::Error::custom("No filter specified")),
+        }
+    }
+
+    // upsert(true) 时带上 upsert 选项，否则使用默认选项
+    fn update_options(&self) -> Option<UpdateOptions> {
+        if self.is_upsert {
+            Some(UpdateOptions::builder().upsert(true).build())
+        } else {
+            None
         }
-        Ok(results)
     }
 
     pub async fn execute_update(&self) -> Result<mongodb::results::UpdateResult> {
         if let Some(filter) = &self.filter {
             let update = self.build_update_doc();
-            let _ = if self.is_upsert {
-                Some(UpdateOptions::builder().upsert(true).build())
-            } else {
-                None
-            };
-            Ok(self.collection.update_one(filter.clone(), update).await?)
+            Ok(self.collection.update_one(filter.clone(), update).with_options(self.update_options()).await?)
+        } else {
+            Err(mongodb::error::Error::custom("No filter specified"))
+        }
+    }
+
+    // 更新并返回文档：return_new 为 true 返回更新后的文档，否则返回更新前的文档
+    pub async fn execute_find_one_and_update(&self, return_new: bool, projection: Option<Document>) -> Result<Option<T>> {
+        if let Some(filter) = &self.filter {
+            let update = self.build_update_doc();
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(if return_new { ReturnDocument::After } else { ReturnDocument::Before })
+                .projection(projection)
+                .upsert(self.is_upsert)
+                .build();
+            Ok(self.collection.find_one_and_update(filter.clone(), update).with_options(options).await?)
         } else {
             Err(mongodb::error::Error::custom("No filter specified"))
         }
@@ -358,5 +1282,363 @@ where
             Err(mongodb::error::Error::custom("No filter specified"))
         }
     }
+
+    // 删除同样要求设置 filter，避免误删整个集合
+    pub async fn execute_delete_one(&self) -> Result<mongodb::results::DeleteResult> {
+        if let Some(filter) = &self.filter {
+            Ok(self.collection.delete_one(filter.clone()).await?)
+        } else {
+            Err(mongodb::error::Error::custom("No filter specified"))
+        }
+    }
+
+    pub async fn execute_delete_many(&self) -> Result<mongodb::results::DeleteResult> {
+        if let Some(filter) = &self.filter {
+            Ok(self.collection.delete_many(filter.clone()).await?)
+        } else {
+            Err(mongodb::error::Error::custom("No filter specified"))
+        }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::Client;
+
+    async fn get_test_database() -> Database {
+        let client = Client::with_uri_str("mongodb://localhost:27017").await.unwrap();
+        client.database("test")
+    }
+
+    #[tokio::test]
+    async fn test_skip_and_limit_stages() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .skip(10)
+            .limit(5);
+
+        assert_eq!(builder.build_pipeline(), vec![
+            doc! { "$skip": 10_i64 },
+            doc! { "$limit": 5_i64 },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_match_stage_keeps_position() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .lookup("users", "user_id", "_id", "user_info")
+            .match_stage(doc! { "user_info.status": "active" })
+            .filter(doc! { "type": "order" });
+
+        let pipeline = builder.build_pipeline();
+        assert_eq!(pipeline.len(), 3);
+        assert_eq!(pipeline[0], doc! { "$match": { "type": "order" } });
+        assert!(pipeline[1].contains_key("$lookup"));
+        assert_eq!(pipeline[2], doc! { "$match": { "user_info.status": "active" } });
+    }
+
+    #[tokio::test]
+    async fn test_max_and_rename_update_operators() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .max(doc! { "high_score": 90 })
+            .rename(doc! { "nick": "nickname" })
+            .current_date(doc! { "updated_at": true });
+
+        let update = builder.build_update_doc();
+        assert_eq!(update.get_document("$max").unwrap(), &doc! { "high_score": 90 });
+        assert_eq!(update.get_document("$rename").unwrap(), &doc! { "nick": "nickname" });
+        assert_eq!(update.get_document("$currentDate").unwrap(), &doc! { "updated_at": true });
+    }
+
+    #[tokio::test]
+    async fn test_update_doc_merges_same_operator() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .set(doc! { "name": "alice" })
+            .inc(doc! { "count": 1 })
+            .set(doc! { "status": "active" });
+
+        assert_eq!(builder.build_update_doc(), doc! {
+            "$set": { "name": "alice", "status": "active" },
+            "$inc": { "count": 1 },
+        });
+    }
+
+    #[tokio::test]
+    async fn test_execute_aggregate_stream_counts_items() {
+        let database = get_test_database().await;
+        let collection: Collection<Document> = database.collection("test_aggregate_stream");
+        collection.delete_many(doc! {}).await.unwrap();
+        collection.insert_many(vec![
+            doc! { "status": "paid", "amount": 10 },
+            doc! { "status": "paid", "amount": 20 },
+            doc! { "status": "pending", "amount": 30 },
+        ]).await.unwrap();
+
+        let builder = MongoAggregateBuilder::new(collection, &database)
+            .filter(doc! { "status": "paid" });
+        let mut orders = builder.execute_aggregate_stream::<Document>().await.unwrap();
+        let mut count = 0;
+        while let Some(order) = orders.try_next().await.unwrap() {
+            assert_eq!(order.get_str("status").unwrap(), "paid");
+            count += 1;
+        }
+        assert_eq!(count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_allow_disk_use_and_batch_size_options() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .hint_index("status_1")
+            .allow_disk_use(true)
+            .batch_size(500);
+
+        let options = builder.aggregate_options.clone().unwrap();
+        assert_eq!(options.allow_disk_use, Some(true));
+        assert_eq!(options.batch_size, Some(500));
+        assert!(matches!(options.hint, Some(Hint::Name(ref name)) if name == "status_1"));
+    }
+
+    #[tokio::test]
+    async fn test_unwind_simple_and_document_forms() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .unwind("tags")
+            .unwind_opts("items", true, Some("item_index"))
+            .unwind_opts("history", false, None);
+
+        assert_eq!(builder.build_pipeline(), vec![
+            doc! { "$unwind": "$tags" },
+            doc! { "$unwind": { "path": "$items", "preserveNullAndEmptyArrays": true, "includeArrayIndex": "item_index" } },
+            doc! { "$unwind": { "path": "$history", "preserveNullAndEmptyArrays": false } },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_merge_into_stage() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .group(doc! { "_id": "$user_id", "total": { "$sum": "$amount" } })
+            .merge_into("user_totals", vec!["_id".to_string()], "replace", "insert");
+
+        let pipeline = builder.build_pipeline();
+        assert_eq!(pipeline[1], doc! {
+            "$merge": {
+                "into": "user_totals",
+                "on": ["_id"],
+                "whenMatched": "replace",
+                "whenNotMatched": "insert",
+            }
+        });
+        assert!(builder.validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_sample_stage() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .filter(doc! { "status": "active" })
+            .sample(5)
+            .unwrap();
+
+        assert_eq!(builder.build_pipeline()[1], doc! { "$sample": { "size": 5_i64 } });
+
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database);
+        assert!(builder.sample(0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_explain_issues_aggregate_explain_command() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .filter(doc! { "status": "paid" })
+            .limit(10)
+            .hint_index("status_1");
+
+        let command = builder.explain_command().unwrap();
+        assert_eq!(command.get_str("verbosity").unwrap(), "queryPlanner");
+        let aggregate = command.get_document("explain").unwrap();
+        assert_eq!(aggregate.get_str("aggregate").unwrap(), "test_collection");
+        assert_eq!(aggregate.get_array("pipeline").unwrap(), &vec![
+            Bson::Document(doc! { "$match": { "status": "paid" } }),
+            Bson::Document(doc! { "$limit": 10_i64 }),
+        ]);
+        assert_eq!(aggregate.get_str("hint").unwrap(), "status_1");
+    }
+
+    #[tokio::test]
+    async fn test_paginate_skip_limit_math() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .filter(doc! { "status": "paid" });
+
+        let (data_pipeline, count_pipeline) = builder.page_pipelines(3, 20).unwrap();
+        assert_eq!(data_pipeline, vec![
+            doc! { "$match": { "status": "paid" } },
+            doc! { "$skip": 40_i64 },
+            doc! { "$limit": 20_i64 },
+        ]);
+        assert_eq!(count_pipeline, vec![
+            doc! { "$match": { "status": "paid" } },
+            doc! { "$count": "total" },
+        ]);
+
+        let (first_page, _) = builder.page_pipelines(0, 20).unwrap();
+        assert_eq!(first_page[1], doc! { "$skip": 0_i64 });
+        assert!(builder.page_pipelines(1, 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delete_without_filter_errors() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database);
+
+        let err = builder.execute_delete_one().await.unwrap_err();
+        assert!(err.to_string().contains("No filter specified"));
+        let err = builder.execute_delete_many().await.unwrap_err();
+        assert!(err.to_string().contains("No filter specified"));
+    }
+
+    #[tokio::test]
+    async fn test_geo_near_placed_before_filter() {
+        let database = get_test_database().await;
+        let near = Bson::Document(doc! { "type": "Point", "coordinates": [116.4, 39.9] });
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .filter(doc! { "open": true })
+            .geo_near(near.clone(), "distance", true, Some(5000.0), None)
+            .limit(20);
+
+        let pipeline = builder.build_pipeline();
+        assert_eq!(pipeline, vec![
+            doc! { "$geoNear": { "near": near.clone(), "distanceField": "distance", "spherical": true, "maxDistance": 5000.0 } },
+            doc! { "$match": { "open": true } },
+            doc! { "$limit": 20_i64 },
+        ]);
+        assert!(builder.validate().is_ok());
+
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .limit(20)
+            .geo_near(near, "distance", true, None, None);
+        assert!(builder.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_set_window_fields_cumulative_sum() {
+        let database = get_test_database().await;
+        let output = doc! {
+            "running_total": {
+                "$sum": "$amount",
+                "window": { "documents": ["unbounded", "current"] }
+            }
+        };
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .set_window_fields(
+                Some(Bson::String("$user_id".to_string())),
+                Some(doc! { "created_at": 1 }),
+                output.clone(),
+            );
+
+        assert_eq!(builder.build_pipeline(), vec![doc! {
+            "$setWindowFields": {
+                "partitionBy": "$user_id",
+                "sortBy": { "created_at": 1 },
+                "output": output,
+            }
+        }]);
+    }
+
+    #[tokio::test]
+    async fn test_project_slice_after_exclusion_project() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .rename_output(&[("name", "userName")])
+            .project_slice("comments", 5);
+
+        assert_eq!(builder.build_pipeline(), vec![
+            doc! { "$addFields": { "userName": "$name" } },
+            doc! { "$project": { "name": 0 } },
+            doc! { "$addFields": { "comments": { "$slice": ["$comments", 5] } } },
+        ]);
+
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .project_slice("comments", 5);
+        assert_eq!(builder.build_pipeline(), vec![
+            doc! { "$addFields": { "comments": { "$slice": ["$comments", 5] } } },
+        ]);
+
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .project(doc! { "_id": 0, "title": 1 })
+            .project_slice("comments", 5);
+        assert_eq!(builder.build_pipeline(), vec![
+            doc! { "$project": { "_id": 0, "title": 1, "comments": { "$slice": ["$comments", 5] } } },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_update_matching_ids_validates_pipeline() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .filter(doc! { "status": "pending" })
+            .out("pending_orders");
+
+        let err = builder.update_matching_ids(doc! { "$set": { "status": "blocked" } }, 100).await.unwrap_err();
+        assert!(err.to_string().contains("$out must be the last stage"));
+    }
+
+    #[tokio::test]
+    async fn test_summary_helpers_validate_pipeline() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .filter(doc! { "status": "pending" })
+            .out("pending_orders");
+
+        let err = builder.paginate::<Document>(1, 20).await.unwrap_err();
+        assert!(err.to_string().contains("$out must be the last stage"));
+        let err = builder.summarize("amount").await.unwrap_err();
+        assert!(err.to_string().contains("$out must be the last stage"));
+        let err = builder.count_distinct("user_id").await.unwrap_err();
+        assert!(err.to_string().contains("$out must be the last stage"));
+    }
+
+    #[tokio::test]
+    async fn test_update_options_carry_upsert() {
+        let database = get_test_database().await;
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .upsert(true);
+        assert_eq!(builder.update_options().and_then(|options| options.upsert), Some(true));
+
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database);
+        assert!(builder.update_options().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_count_bucket_sort_by_count_graph_lookup_out_stages() {
+        let database = get_test_database().await;
+        let bucket = doc! { "groupBy": "$price", "boundaries": [0, 100], "default": "other" };
+        let graph = doc! {
+            "from": "employees",
+            "startWith": "$manager_id",
+            "connectFromField": "manager_id",
+            "connectToField": "_id",
+            "as": "managers"
+        };
+        let builder: MongoAggregateBuilder<Document> = MongoAggregateBuilder::new(database.collection("test_collection"), &database)
+            .bucket(bucket.clone())
+            .sort_by_count("$category")
+            .graph_lookup(graph.clone())
+            .count("total")
+            .out("report");
+
+        assert_eq!(builder.build_pipeline(), vec![
+            doc! { "$bucket": bucket },
+            doc! { "$sortByCount": "$category" },
+            doc! { "$graphLookup": graph },
+            doc! { "$count": "total" },
+            doc! { "$out": "report" },
+        ]);
+    }
+}
@@ -3,7 +3,7 @@
 //! 这个模块提供了一个流式API来构建和执行MongoDB的批量写入操作。
 //! 支持以下操作:
 //! 
-//! - 更新操作(UpdateOne):
+//! - 更新操作(UpdateOne/UpdateMany):
 //!   - set: 设置字段值
 //!   - unset: 删除字段
 //!   - setOnInsert: 仅在插入时设置字段值
@@ -25,19 +25,28 @@
 //! - 插入操作:
 //!   - insertOne: 插入单个文档
 //!
+//! - 替换操作:
+//!   - replaceOne: 整文档替换
+//!
 //! 所有操作都可以链式调用,最后通过execute()执行批量写入。
 //! 支持自动合并相同类型的更新操作以提高性能。
 
 use mongodb::{
-    bson::{Document, to_document, Bson,doc},
+    bson::{Document, to_document, Bson, DateTime, doc},
     Collection,
-    options::{WriteModel, UpdateOneModel, DeleteOneModel, 
-        DeleteManyModel, InsertOneModel, UpdateModifications},
+    ClientSession,
+    options::{BulkWriteOptions, Collation, Hint, WriteConcern, WriteModel, UpdateOneModel, DeleteOneModel, 
+        DeleteManyModel, InsertOneModel, ReplaceOneModel, UpdateManyModel, UpdateModifications},
     results::SummaryBulkWriteResult,
     error::Error,
     error::ErrorKind,
 };
 use serde::{Serialize, de::DeserializeOwned};
+use std::time::Duration;
+use crate::mongo_query_filter::struct_to_filter;
+
+// 幂等执行标记所在的集合（与目标集合同库）
+const IDEMPOTENCY_COLLECTION: &str = "bulk_write_idempotency";
 
 #[derive(Debug, Clone)]
 pub enum WriteOperation<T> where
@@ -49,16 +58,53 @@ pub enum WriteOperation<T> where
         filter: Document,
         updates: Vec<UpdateType>,  // 存储 UpdateType 而不是 Document
         upsert: bool,
+        options: OperationOptions,
+    },
+    UpdateMany {
+        filter: Document,
+        updates: Vec<UpdateType>,
+        upsert: bool,
+        options: OperationOptions,
     },
     DeleteOne {
         filter: Document,
+        options: OperationOptions,
     },
     DeleteMany {
         filter: Document,
+        options: OperationOptions,
     },
     InsertOne {
         document: T,
     },
+    ReplaceOne {
+        filter: Document,
+        replacement: T,
+        upsert: bool,
+    },
+}
+
+// 作用于整个操作（而不是某个 UpdateType）的选项
+#[derive(Debug, Clone, Default)]
+pub struct OperationOptions {
+    pub array_filters: Option<Vec<Document>>,
+    pub collation: Option<Collation>,
+    pub hint: Option<Hint>,
+}
+
+impl OperationOptions {
+    // 同一 filter 的操作合并时，后设置的选项覆盖先设置的
+    fn merge(&mut self, other: OperationOptions) {
+        if other.array_filters.is_some() {
+            self.array_filters = other.array_filters;
+        }
+        if other.collation.is_some() {
+            self.collation = other.collation;
+        }
+        if other.hint.is_some() {
+            self.hint = other.hint;
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -83,22 +129,152 @@ pub enum UpdateType {
 }
 
 impl UpdateType {
+    // 支持数值（含 Int32/Int64/Double 之间的比较）、日期、字符串、时间戳，类型不可比较时返回 None
     fn compare_bson_values(v1: &Bson, v2: &Bson) -> Option<std::cmp::Ordering> {
         match (v1, v2) {
             (Bson::Double(d1), Bson::Double(d2)) => Some(d1.partial_cmp(d2)?),
             (Bson::Int32(i1), Bson::Int32(i2)) => Some(i1.cmp(i2)),
             (Bson::Int64(i1), Bson::Int64(i2)) => Some(i1.cmp(i2)),
+            (Bson::Int32(i1), Bson::Int64(i2)) => Some((*i1 as i64).cmp(i2)),
+            (Bson::Int64(i1), Bson::Int32(i2)) => Some(i1.cmp(&(*i2 as i64))),
+            (Bson::Int32(_) | Bson::Int64(_), Bson::Double(_))
+            | (Bson::Double(_), Bson::Int32(_) | Bson::Int64(_)) => {
+                let to_f64 = |v: &Bson| match v {
+                    Bson::Int32(i) => Some(*i as f64),
+                    Bson::Int64(i) => Some(*i as f64),
+                    Bson::Double(d) => Some(*d),
+                    _ => None,
+                };
+                to_f64(v1)?.partial_cmp(&to_f64(v2)?)
+            }
+            (Bson::DateTime(d1), Bson::DateTime(d2)) => Some(d1.timestamp_millis().cmp(&d2.timestamp_millis())),
+            (Bson::String(s1), Bson::String(s2)) => Some(s1.cmp(s2)),
+            (Bson::Timestamp(t1), Bson::Timestamp(t2)) => Some((t1.time, t1.increment).cmp(&(t2.time, t2.increment))),
             _ => None,
         }
     }
 
+    /*
+        Inc/Mul 合并时的数值计算，按 MongoDB 的类型提升规则：有 Double 结果为 Double，
+        否则有 Int64 结果为 Int64，两个 Int32 溢出时也提升为 Int64；非数值或 Int64 溢出返回 None，两个更新不合并
+     */
+    fn combine_numbers(existing: &Bson, new: &Bson, multiply: bool) -> Option<Bson> {
+        let result = match (existing, new) {
+            (Bson::Int32(a), Bson::Int32(b)) => {
+                let combined = if multiply { a.checked_mul(*b) } else { a.checked_add(*b) };
+                match combined {
+                    Some(value) => Bson::Int32(value),
+                    None => return Self::combine_numbers(&Bson::Int64(*a as i64), new, multiply),
+                }
+            }
+            (Bson::Int32(_) | Bson::Int64(_), Bson::Int32(_) | Bson::Int64(_)) => {
+                let to_i64 = |v: &Bson| match v {
+                    Bson::Int32(i) => *i as i64,
+                    Bson::Int64(i) => *i,
+                    _ => 0,
+                };
+                let (a, b) = (to_i64(existing), to_i64(new));
+                Bson::Int64(if multiply { a.checked_mul(b)? } else { a.checked_add(b)? })
+            }
+            (Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_), Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_)) => {
+                let to_f64 = |v: &Bson| match v {
+                    Bson::Int32(i) => *i as f64,
+                    Bson::Int64(i) => *i as f64,
+                    Bson::Double(d) => *d,
+                    _ => 0.0,
+                };
+                let (a, b) = (to_f64(existing), to_f64(new));
+                Bson::Double(if multiply { a * b } else { a + b })
+            }
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    /*
+        合并同一字段上的两个 $bit 操作：and+and 掩码相与，or+or 掩码相或，xor+xor 掩码异或
+        不同的位运算（如 and 之后 or）合成后语义会改变，返回 None
+     */
+    fn compose_bit(existing: &Bson, new: &Bson) -> Option<Bson> {
+        let (Bson::Document(existing), Bson::Document(new)) = (existing, new) else {
+            return None;
+        };
+        if existing.len() != 1 || new.len() != 1 {
+            return None;
+        }
+        let (op1, mask1) = existing.iter().next()?;
+        let (op2, mask2) = new.iter().next()?;
+        if op1 != op2 {
+            return None;
+        }
+        let compose = |a: i64, b: i64| match op1.as_str() {
+            "and" => Some(a & b),
+            "or" => Some(a | b),
+            "xor" => Some(a ^ b),
+            _ => None,
+        };
+        let mask = match (mask1, mask2) {
+            (Bson::Int32(a), Bson::Int32(b)) => Bson::Int32(compose(*a as i64, *b as i64)? as i32),
+            (Bson::Int32(a), Bson::Int64(b)) => Bson::Int64(compose(*a as i64, *b)?),
+            (Bson::Int64(a), Bson::Int32(b)) => Bson::Int64(compose(*a, *b as i64)?),
+            (Bson::Int64(a), Bson::Int64(b)) => Bson::Int64(compose(*a, *b)?),
+            _ => return None,
+        };
+        Some(Bson::Document(doc! { op1.as_str(): mask }))
+    }
+
     /*
     对于简单覆盖型操作（Set, Unset, SetOnInsert, Rename, Bit），合并所有字段
     对于数组操作（Push, PushEach, Pull, PullAll, AddToSet, AddToSetEach），正确处理数组元素
     对于数值操作（Inc, Mul），正确处理不同数值类型的计算
     对于比较操作（Min, Max），保持正确的比较语义
-    对于特殊操作（Pop, CurrentDate），使用最后一个操作的值
+    对于 Pop 按字段合并，同一字段使用最后一个操作的方向
+    对于 CurrentDate，使用最后一个操作的值
      */
+    // push/addToSet 的已有值（单个值、数组或 { $each: [...] }）与新值依次拼成元素列表，数组按多个元素处理
+    fn append_items(existing: &Bson, new: &Bson) -> Vec<Bson> {
+        let items = |value: &Bson| match value {
+            Bson::Document(d) if d.contains_key("$each") => d.get_array("$each").cloned().unwrap_or_default(),
+            Bson::Array(arr) => arr.clone(),
+            other => vec![other.clone()],
+        };
+        let mut combined = items(existing);
+        combined.extend(items(new));
+        combined
+    }
+
+    fn combine_pull_conditions(existing: &Bson, new: &Bson) -> Option<Bson> {
+        // 字段查询文档：所有键都是字段名（数组元素是文档时使用）
+        let field_query = |condition: &Document| !condition.is_empty() && condition.keys().all(|key| !key.starts_with('$'));
+        // 相等条件展开成 $in 的元素列表
+        let equality_values = |condition: &Bson| match condition {
+            Bson::Document(d) if d.len() == 1 && d.contains_key("$in") => d.get_array("$in").ok().cloned(),
+            Bson::Document(d) if d.len() == 1 && d.contains_key("$eq") => d.get("$eq").map(|value| vec![value.clone()]),
+            Bson::Document(_) => None,
+            other => Some(vec![other.clone()]),
+        };
+
+        if let (Bson::Document(existing), Bson::Document(new_condition)) = (existing, new) {
+            if field_query(new_condition) {
+                let mut conditions = match existing.get_array("$or") {
+                    Ok(conditions) if existing.len() == 1 => conditions.clone(),
+                    _ if field_query(existing) => vec![Bson::Document(existing.clone())],
+                    _ => return None,
+                };
+                conditions.push(Bson::Document(new_condition.clone()));
+                return Some(Bson::Document(doc! { "$or": conditions }));
+            }
+        }
+
+        let mut values = equality_values(existing)?;
+        for value in equality_values(new)? {
+            if !values.iter().any(|seen| bson_equal(seen, &value)) {
+                values.push(value);
+            }
+        }
+        Some(Bson::Document(doc! { "$in": values }))
+    }
+
     fn merge(&self, other: &UpdateType) -> Option<UpdateType> {
         match (self, other) {
             // Set: 后面的值覆盖前面的值
@@ -175,21 +351,19 @@ impl UpdateType {
             builder.push(doc! { "tags": ["mongodb", "database"] })
             // 生成: { $push: { tags: { $each: ["mongodb", "database"] } } }
              */
-            // Push: 合并数组
+            // Push: 合并数组，同一字段两次 push 的元素依次拼接到 $each 中
             (UpdateType::Push(doc1), UpdateType::Push(doc2)) => {
                 let mut merged = doc1.clone();
                 for (k, v) in doc2.iter() {
-                    match v {
+                    let value = match merged.get(k) {
+                        Some(existing) => Bson::Document(doc! { "$each": Self::append_items(existing, v) }),
                         // 如果是数组，应该转换为 $each 格式
-                        Bson::Array(arr) => {
-                            let each_doc = doc! { "$each": arr };
-                            merged.insert(k, Bson::Document(each_doc));
+                        None => match v {
+                            Bson::Array(arr) => Bson::Document(doc! { "$each": arr }),
+                            _ => v.clone(),
                         },
-                        // 如果是单个值，直接使用
-                        _ => {
-                            merged.insert(k, v.clone());
-                        }
-                    }
+                    };
+                    merged.insert(k, value);
                 }
                 Some(UpdateType::Push(merged))
             },
@@ -267,21 +441,30 @@ impl UpdateType {
                 Some(UpdateType::PushEach(merged))
             },
     
-            // Pull: 使用最新的条件
+            // Pull: 同一字段的两个条件都是字段查询文档时用 $or 合并（满足任一条件的元素都被移除），
+            // 都是相等条件（标量、$eq、$in）时合并成一个 $in；$gt 等操作符条件无法合成一个合法条件，不合并
             /*
             builder.add_update(filter)
-            .pull(doc! { "scores": { "$gt": 5 } })
-            .pull(doc! { "fruits": "apple" })
+            .pull(doc! { "items": { "status": "expired" } })
+            .pull(doc! { "items": { "qty": 0 } })
+            // 生成: { $pull: { items: { $or: [{ status: "expired" }, { qty: 0 }] } } }
+            .pull(doc! { "tags": "a" })
+            .pull(doc! { "tags": { "$in": ["b"] } })
+            // 生成: { $pull: { tags: { $in: ["a", "b"] } } }
              */
             (UpdateType::Pull(doc1), UpdateType::Pull(doc2)) => {
                 let mut merged = doc1.clone();
                 for (k, v) in doc2.iter() {
-                    merged.insert(k, v.clone());  // 使用最新的条件
+                    let value = match merged.get(k) {
+                        Some(existing) => Self::combine_pull_conditions(existing, v)?,
+                        None => v.clone(),
+                    };
+                    merged.insert(k, value);
                 }
                 Some(UpdateType::Pull(merged))
             },
     
-            // PullAll: 合并要移除的元素数组
+            // PullAll: 合并要移除的元素数组，去重并保持先后顺序
             /*
                 builder.pull_all(doc! { "fruits": ["apple", "banana"] })
              */
@@ -290,7 +473,11 @@ impl UpdateType {
                 for (k, v) in doc2.iter() {
                     if let (Some(Bson::Array(existing)), Some(new_values)) = (merged.get(k).map(|v| v.clone()), v.as_array()) {
                         let mut combined = existing.clone();
-                        combined.extend(new_values.iter().cloned());
+                        for value in new_values {
+                            if !combined.contains(value) {
+                                combined.push(value.clone());
+                            }
+                        }
                         merged.insert(k, Bson::Array(combined));
                     } else {
                         merged.insert(k, v.clone());
@@ -299,9 +486,13 @@ impl UpdateType {
                 Some(UpdateType::PullAll(merged))
             },
     
-            // Pop: 不合并，使用最后一个操作
-            (UpdateType::Pop(_), UpdateType::Pop(doc2)) => {
-                Some(UpdateType::Pop(doc2.clone()))
+            // Pop: 按字段合并，不同字段都保留，同一字段使用最后一次的方向
+            (UpdateType::Pop(doc1), UpdateType::Pop(doc2)) => {
+                let mut merged = doc1.clone();
+                for (k, v) in doc2.iter() {
+                    merged.insert(k, v.clone());
+                }
+                Some(UpdateType::Pop(merged))
             },
     
             // AddToSet: 将值作为单个元素添加，保持唯一性
@@ -317,20 +508,27 @@ impl UpdateType {
             // 生成: { $addToSet: { tags: { $each: ["mongodb", "database"] } } }
 
              */
+            // 同一字段两次 addToSet 的元素合并到 $each 中并去重
             (UpdateType::AddToSet(doc1), UpdateType::AddToSet(doc2)) => {
                 let mut merged = doc1.clone();
                 for (k, v) in doc2.iter() {
-                    match v {
+                    let value = match merged.get(k) {
+                        Some(existing) => {
+                            let mut items: Vec<Bson> = Vec::new();
+                            for item in Self::append_items(existing, v) {
+                                if !items.iter().any(|seen| bson_equal(seen, &item)) {
+                                    items.push(item);
+                                }
+                            }
+                            Bson::Document(doc! { "$each": items })
+                        }
                         // 如果是数组，应该转换为 $each 格式
-                        Bson::Array(arr) => {
-                            let each_doc = doc! { "$each": arr };
-                            merged.insert(k, Bson::Document(each_doc));
+                        None => match v {
+                            Bson::Array(arr) => Bson::Document(doc! { "$each": arr }),
+                            _ => v.clone(),
                         },
-                        // 如果是单个值，直接使用
-                        _ => {
-                            merged.insert(k, v.clone());
-                        }
-                    }
+                    };
+                    merged.insert(k, value);
                 }
                 Some(UpdateType::AddToSet(merged))
             },
@@ -371,7 +569,13 @@ impl UpdateType {
                         other => doc! { "$each": [other.clone()] },
                     };
 
-                    if let Some(Bson::Document(existing)) = merged.get(k).map(|v| v.clone()) {
+                    // 已有的值同样规整为 $each 格式（第一次调用可能传入的是数组或单个值）
+                    let existing = merged.get(k).map(|value| match value {
+                        Bson::Document(d) if d.contains_key("$each") => d.clone(),
+                        Bson::Array(arr) => doc! { "$each": arr },
+                        other => doc! { "$each": [other.clone()] },
+                    });
+                    if let Some(existing) = existing {
                         let mut combined = existing.clone();
                         // 合并 $each 数组，保持唯一性
                         let existing_arr = existing.get("$each")
@@ -384,10 +588,10 @@ impl UpdateType {
                             .clone();
                         
                         let mut merged_arr = existing_arr;
-                        // 保持唯一性
+                        // 保持唯一性，嵌套文档按字段顺序无关的方式比较
                         for new_v in new_arr {
-                            if !merged_arr.contains(&new_v) {
-                                merged_arr.push(new_v.clone());
+                            if !merged_arr.iter().any(|existing_v| bson_equal(existing_v, &new_v)) {
+                                merged_arr.push(new_v);
                             }
                         }
                         combined.insert("$each", Bson::Array(merged_arr));
@@ -417,15 +621,11 @@ impl UpdateType {
             (UpdateType::Inc(doc1), UpdateType::Inc(doc2)) => {
                 let mut merged = doc1.clone();
                 for (k, v) in doc2.iter() {
-                    if let (Some(Bson::Double(existing)), Some(new_value)) = (merged.get(k).map(|v| v.clone()), v.as_f64()) {
-                        merged.insert(k, Bson::Double(existing + new_value));
-                    } else if let (Some(Bson::Int32(existing)), Some(new_value)) = (merged.get(k).map(|v| v.clone()), v.as_i32()) {
-                        merged.insert(k, Bson::Int32(existing + new_value));
-                    } else if let (Some(Bson::Int64(existing)), Some(new_value)) = (merged.get(k).map(|v| v.clone()), v.as_i64()) {
-                        merged.insert(k, Bson::Int64(existing + new_value));
-                    } else {
-                        merged.insert(k, v.clone());
-                    }
+                    let value = match merged.get(k) {
+                        Some(existing) => Self::combine_numbers(existing, v, false)?,
+                        None => v.clone(),
+                    };
+                    merged.insert(k, value);
                 }
                 Some(UpdateType::Inc(merged))
             },
@@ -434,15 +634,11 @@ impl UpdateType {
             (UpdateType::Mul(doc1), UpdateType::Mul(doc2)) => {
                 let mut merged = doc1.clone();
                 for (k, v) in doc2.iter() {
-                    if let (Some(Bson::Double(existing)), Some(new_value)) = (merged.get(k).map(|v| v.clone()), v.as_f64()) {
-                        merged.insert(k, Bson::Double(existing * new_value));
-                    } else if let (Some(Bson::Int32(existing)), Some(new_value)) = (merged.get(k).map(|v| v.clone()), v.as_i32()) {
-                        merged.insert(k, Bson::Int32(existing * new_value));
-                    } else if let (Some(Bson::Int64(existing)), Some(new_value)) = (merged.get(k).map(|v| v.clone()), v.as_i64()) {
-                        merged.insert(k, Bson::Int64(existing * new_value));
-                    } else {
-                        merged.insert(k, v.clone());
-                    }
+                    let value = match merged.get(k) {
+                        Some(existing) => Self::combine_numbers(existing, v, true)?,
+                        None => v.clone(),
+                    };
+                    merged.insert(k, value);
                 }
                 Some(UpdateType::Mul(merged))
             },
@@ -495,11 +691,15 @@ impl UpdateType {
                 Some(UpdateType::CurrentDate(doc2.clone()))
             },
     
-            // Bit: 按位操作合并
+            // Bit: 同一字段的位运算按 compose_bit 合成，无法安全合成时整体不合并
             (UpdateType::Bit(doc1), UpdateType::Bit(doc2)) => {
                 let mut merged = doc1.clone();
                 for (k, v) in doc2.iter() {
-                    merged.insert(k, v.clone());
+                    let value = match merged.get(k) {
+                        Some(existing) => Self::compose_bit(existing, v)?,
+                        None => v.clone(),
+                    };
+                    merged.insert(k, value);
                 }
                 Some(UpdateType::Bit(merged))
             },
@@ -508,6 +708,40 @@ impl UpdateType {
             _ => None
         }
     }
+    fn fields(&self) -> &Document {
+        match self {
+            UpdateType::Set(doc) | UpdateType::Unset(doc) | UpdateType::SetOnInsert(doc)
+            | UpdateType::Push(doc) | UpdateType::PushEach(doc) | UpdateType::Pull(doc)
+            | UpdateType::PullAll(doc) | UpdateType::Pop(doc) | UpdateType::AddToSet(doc)
+            | UpdateType::AddToSetEach(doc) | UpdateType::Inc(doc) | UpdateType::Mul(doc)
+            | UpdateType::Min(doc) | UpdateType::Max(doc) | UpdateType::Rename(doc)
+            | UpdateType::CurrentDate(doc) | UpdateType::Bit(doc) => doc,
+        }
+    }
+
+    // 同一种操作换成另一组字段
+    fn with_fields(&self, fields: Document) -> UpdateType {
+        match self {
+            UpdateType::Set(_) => UpdateType::Set(fields),
+            UpdateType::Unset(_) => UpdateType::Unset(fields),
+            UpdateType::SetOnInsert(_) => UpdateType::SetOnInsert(fields),
+            UpdateType::Push(_) => UpdateType::Push(fields),
+            UpdateType::PushEach(_) => UpdateType::PushEach(fields),
+            UpdateType::Pull(_) => UpdateType::Pull(fields),
+            UpdateType::PullAll(_) => UpdateType::PullAll(fields),
+            UpdateType::Pop(_) => UpdateType::Pop(fields),
+            UpdateType::AddToSet(_) => UpdateType::AddToSet(fields),
+            UpdateType::AddToSetEach(_) => UpdateType::AddToSetEach(fields),
+            UpdateType::Inc(_) => UpdateType::Inc(fields),
+            UpdateType::Mul(_) => UpdateType::Mul(fields),
+            UpdateType::Min(_) => UpdateType::Min(fields),
+            UpdateType::Max(_) => UpdateType::Max(fields),
+            UpdateType::Rename(_) => UpdateType::Rename(fields),
+            UpdateType::CurrentDate(_) => UpdateType::CurrentDate(fields),
+            UpdateType::Bit(_) => UpdateType::Bit(fields),
+        }
+    }
+
     fn to_document(&self) -> Document {
         match self {
             UpdateType::Set(doc) => doc! { "$set": doc },
@@ -553,10 +787,139 @@ impl UpdateType {
     }
 }
 
+// 字段顺序无关的比较：嵌套文档递归比较，数组按元素顺序逐个比较（数组顺序有语义）
+// 用于判断 filter 是否相同以及 addToSet 的 $each 去重
+fn documents_equal(doc1: &Document, doc2: &Document) -> bool {
+    if doc1.len() != doc2.len() {
+        return false;
+    }
+    
+    for (key, value1) in doc1.iter() {
+        match doc2.get(key) {
+            Some(value2) if bson_equal(value1, value2) => continue,
+            _ => return false,
+        }
+    }
+    true
+}
+
+fn bson_equal(value1: &Bson, value2: &Bson) -> bool {
+    match (value1, value2) {
+        (Bson::Document(d1), Bson::Document(d2)) => documents_equal(d1, d2),
+        (Bson::Array(a1), Bson::Array(a2)) => {
+            a1.len() == a2.len() && a1.iter().zip(a2).all(|(v1, v2)| bson_equal(v1, v2))
+        }
+        _ => value1 == value2,
+    }
+}
+
+// 空 filter 的处理策略：Skip 静默跳过（默认），Error 直接返回错误并指出是哪个操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmptyFilterPolicy {
+    #[default]
+    Skip,
+    Error,
+}
+
 struct BatchUpdateContext{
     filter: Document,
     updates: Vec<UpdateType>,
     is_upsert: bool,
+    skip: bool,  // maybe_update(false, ..) 产生的占位上下文，提交时直接丢弃
+    merge: bool, // false 时每个更新都按顺序追加，不做合并
+    position: Option<usize>,  // 复用已有 UpdateOne/UpdateMany 时其在 operations 中的位置
+    many: bool,  // true 时提交为 UpdateMany
+    options: OperationOptions,
+}
+
+// execute 结果的简化版本，计数统一为 u64
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BulkSummary {
+    pub inserted: u64,
+    pub matched: u64,
+    pub modified: u64,
+    pub upserted: u64,
+    pub deleted: u64,
+}
+
+impl From<&SummaryBulkWriteResult> for BulkSummary {
+    fn from(summary: &SummaryBulkWriteResult) -> Self {
+        let count = |n: i64| n.max(0) as u64;
+        Self {
+            inserted: count(summary.inserted_count),
+            matched: count(summary.matched_count),
+            modified: count(summary.modified_count),
+            upserted: count(summary.upserted_count),
+            deleted: count(summary.deleted_count),
+        }
+    }
+}
+
+// execute_chunked 的失败结果：summary 是已经成功写入的批次计数之和，errors 是各失败批次的错误
+// 构建阶段的错误（chunk_size 为 0、空 filter 等）summary 为空，errors 只有这一个错误
+#[derive(Debug)]
+pub struct ChunkedWriteError {
+    pub summary: SummaryBulkWriteResult,
+    pub errors: Vec<Error>,
+}
+
+impl From<Error> for ChunkedWriteError {
+    fn from(error: Error) -> Self {
+        Self { summary: SummaryBulkWriteResult::default(), errors: vec![error] }
+    }
+}
+
+impl std::fmt::Display for ChunkedWriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} chunk(s) failed", self.errors.len())?;
+        if let Some(first) = self.errors.first() {
+            write!(f, ", first error: {}", first)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ChunkedWriteError {}
+
+// run 的失败结果：原始错误，以及映射回原始 filter/更新的失败操作（非写入错误时为空）
+#[derive(Debug)]
+pub struct RunError<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    pub error: Error,
+    pub failed: Vec<FailedOperation<T>>,
+}
+
+impl<T> std::fmt::Display for RunError<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({} failed operation(s))", self.error, self.failed.len())
+    }
+}
+
+impl<T> std::error::Error for RunError<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + std::fmt::Debug + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// 批量写入中失败的一条操作，operation 为 None 表示来自 add_raw_model 的原始模型
+#[derive(Debug, Clone)]
+pub struct FailedOperation<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    pub model_index: usize,
+    pub operation_index: Option<usize>,
+    pub operation: Option<WriteOperation<T>>,
+    pub code: i32,
+    pub message: String,
 }
 
 pub struct BatchUpdateBuilder<T>
@@ -565,6 +928,21 @@ where
 {
     operations: Vec<WriteOperation<T>>,
     ordered: bool,
+    empty_filter_policy: EmptyFilterPolicy,
+    merge_enabled: bool,
+    raw_models: Vec<(usize, WriteModel)>,  // (加入时 operations 的长度, 原始模型)，execute 时按位置插回
+    idempotency_key: Option<String>,
+    timestamp_field: Option<String>,
+    updated_field: Option<String>,
+    write_concern: Option<WriteConcern>,  // None 时使用集合/客户端配置的写关注
+    bypass_document_validation: bool,
+    base_filter: Option<Document>,
+    safe_delete: bool,
+    confirmed_deletes: Vec<usize>,  // confirm_delete_many(true) 确认过的 DeleteMany 在 operations 中的位置
+    last_delete: Option<usize>,  // 上一个调用刚加入的 delete/delete_many 的下标，collation/hint 只作用于它，其他调用后清空
+    model_sources: Vec<Option<usize>>,  // 上次发送的 write_models 中每个位置对应的 operations 下标，原始模型为 None
+    in_update_scope: bool,  // add_update_with 的闭包正在执行，禁止嵌套
+    nested_update_scope: bool,  // 出现过嵌套的 add_update_with，build_models 返回错误
     current_context: Option<BatchUpdateContext>,
     collection: Collection<T>,
 }
@@ -577,462 +955,2601 @@ where
         Self {
             operations: Vec::new(),
             ordered: true,
+            empty_filter_policy: EmptyFilterPolicy::Skip,
+            merge_enabled: true,
+            raw_models: Vec::new(),
+            idempotency_key: None,
+            timestamp_field: None,
+            updated_field: None,
+            write_concern: None,
+            bypass_document_validation: false,
+            base_filter: None,
+            safe_delete: false,
+            confirmed_deletes: Vec::new(),
+            last_delete: None,
+            model_sources: Vec::new(),
+            in_update_scope: false,
+            nested_update_scope: false,
             current_context: None,
             collection: collection,
         }
     }
     
 
-    pub fn ordered(mut self, ordered: bool) -> Self {
-        self.ordered = ordered;
-        self
+    // 待执行的操作数：已提交的操作、原始模型，以及尚未提交且有更新内容的新上下文
+    pub fn len(&self) -> usize {
+        let pending = self.current_context.as_ref()
+            .map(|context| !context.skip && context.position.is_none() && !context.updates.is_empty())
+            .unwrap_or(false);
+        self.operations.len() + self.raw_models.len() + pending as usize
     }
 
-    fn commit_context(&mut self) {
-        if let Some(context) = self.current_context.take() {
-            if !context.updates.is_empty() {
-                self.operations.push(WriteOperation::UpdateOne {
-                    filter: context.filter,
-                    updates: context.updates,
-                    upsert: context.is_upsert,
-                });
-            }
-        }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
-
-
-    fn documents_equal(doc1: &Document, doc2: &Document) -> bool {
-        if doc1.len() != doc2.len() {
-            return false;
-        }
-        
-        for (key, value1) in doc1.iter() {
-            match doc2.get(key) {
-                Some(value2) if value1 == value2 => continue,
-                _ => return false,
-            }
+    /*
+        清空所有操作和当前上下文，保留集合以及 ordered 等设置，便于执行后复用
+        if builder.len() >= 1000 {
+            builder.execute().await?;
+            builder.clear();
         }
-        true
-    }
-
-    pub fn add_update(&mut self, filter: Document) -> &mut Self {
-        self.add_upsert(filter,false)
+     */
+    pub fn clear(&mut self) {
+        self.operations.clear();
+        self.raw_models.clear();
+        self.confirmed_deletes.clear();
+        self.last_delete = None;
+        self.model_sources.clear();
+        self.nested_update_scope = false;
+        self.current_context = None;
     }
 
-    pub fn add_upsert(&mut self, filter: Document,upsert: bool) -> &mut Self {
-        // 提交当前上下文
-        self.commit_context();
-
-        // 查找是否存在相同filter的操作
-        let existing_update = self.operations.iter()
-            .position(|op| {
-                if let WriteOperation::UpdateOne { filter: existing_filter, .. } = op {
-                    Self::documents_equal(existing_filter, &filter)
-                } else {
-                    false
-                }
-            })
-            .map(|index| self.operations.remove(index));
-
-        // 根据查找结果创建新的上下文
-        self.current_context = Some(match existing_update {
-            Some(WriteOperation::UpdateOne { updates, upsert, .. }) => BatchUpdateContext {
-                filter,
-                updates,  // 使用已存在的updates
-                is_upsert: upsert,
-            },
-            _ => BatchUpdateContext {
-                filter,
-                updates: Vec::new(),
-                is_upsert: upsert,
-            }
-        });
-
+    pub fn ordered(mut self, ordered: bool) -> Self {
+        self.ordered = ordered;
         self
     }
 
-    pub fn set(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::Set(doc));
-        }
+    /*
+        批量写入的写关注，不设置时沿用客户端配置
+        let builder = BatchUpdateBuilder::new(collection).write_concern(WriteConcern::majority());
+     */
+    pub fn write_concern(mut self, write_concern: WriteConcern) -> Self {
+        self.write_concern = Some(write_concern);
         self
     }
 
-    pub fn unset(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::Unset(doc));
-        }
+    // 跳过集合的 schema 校验，用于导入暂时不满足校验规则的数据，默认 false
+    pub fn bypass_document_validation(mut self, bypass: bool) -> Self {
+        self.bypass_document_validation = bypass;
         self
     }
 
-    pub fn set_on_insert(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::SetOnInsert(doc));
-        }
-        self
+    // 所有 bulk_write 调用共用的选项
+    fn bulk_write_options(&self) -> BulkWriteOptions {
+        BulkWriteOptions::builder()
+            .ordered(self.ordered)
+            .write_concern(self.write_concern.clone())
+            .bypass_document_validation(self.bypass_document_validation.then_some(true))
+            .build()
     }
 
-    pub fn push(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::Push(doc));
-        }
+    // 插入（包括 upsert 插入）时自动写入创建时间字段，已存在的值不会被覆盖
+    pub fn auto_timestamp(mut self, field: impl Into<String>) -> Self {
+        let field = field.into();
+        assert!(
+            self.updated_field.as_deref() != Some(field.as_str()),
+            "Created and updated timestamp fields must differ, both are '{}'", field
+        );
+        self.timestamp_field = Some(field);
         self
     }
 
-    pub fn push_each(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::PushEach(doc));
+    /*
+        每个更新都用 $currentDate 写入 updated_field，created_field 与 auto_timestamp 相同：
+        插入和 upsert 插入时写入创建时间
+        let builder = BatchUpdateBuilder::new(collection).with_timestamps("updated_at", Some("created_at"));
+        已经通过 current_date/set/unset/rename 写了 updated_field 的更新保持原样
+        两个字段相同时 upsert 会同时产生 $currentDate 和 $setOnInsert，服务端拒绝，这里直接 panic
+     */
+    pub fn with_timestamps(mut self, updated_field: &str, created_field: Option<&str>) -> Self {
+        if let Some(created_field) = created_field {
+            self.timestamp_field = Some(created_field.to_string());
         }
+        assert!(
+            self.timestamp_field.as_deref() != Some(updated_field),
+            "Created and updated timestamp fields must differ, both are '{}'", updated_field
+        );
+        self.updated_field = Some(updated_field.to_string());
         self
     }
 
-    pub fn pull(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::Pull(doc));
-        }
+    /*
+        公共 filter（如租户），合并到之后每个 add_update/add_upsert/delete/delete_many 的 filter 中：
+        - 字段不重叠或值相同：直接合并成一个文档 { tenant: X, id: 1 }
+        - 同一字段值不同：使用 { $and: [base, filter] }，两者都必须满足，单个操作无法绕过公共条件
+        - 空 filter 保持为空，仍按 on_empty_filter 跳过或报错，不会变成整个租户范围的更新/删除
+        let mut builder = BatchUpdateBuilder::new(collection).with_base_filter(doc! { "tenant": tenant_id });
+     */
+    pub fn with_base_filter(mut self, base: Document) -> Self {
+        self.base_filter = Some(base);
         self
     }
 
-    pub fn pull_all(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::PullAll(doc));
+    fn scoped_filter(&self, filter: Document) -> Document {
+        let base = match &self.base_filter {
+            Some(base) if !base.is_empty() => base,
+            _ => return filter,
+        };
+        if filter.is_empty() {
+            return filter;
         }
-        self
+        let conflict = base.iter().any(|(k, v)| filter.get(k).map(|existing| existing != v).unwrap_or(false));
+        if conflict {
+            return doc! { "$and": [base.clone(), filter] };
+        }
+        let mut merged = base.clone();
+        for (k, v) in filter {
+            merged.insert(k, v);
+        }
+        merged
     }
 
-    pub fn pop(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::Pop(doc));
-        }
+    /*
+        安全删除模式：每个 delete_many 的 filter 必须是"有选择性"的，否则需要紧跟 confirm_delete_many(true)，
+        否则 execute 返回错误
+        有选择性的 filter：至少有一个顶层字段（非 $ 开头）是等值匹配（标量值，或包含 $eq/$in 的条件），
+        $and 中任一子条件有选择性也算；匹配全部的正则（""、".*"）以及单独的 $exists/$ne 不算
+        let mut builder = BatchUpdateBuilder::new(collection).safe_delete(true);
+        builder.delete_many(doc! { "user_id": id });                              // 通过
+        builder.delete_many(doc! { "expired": { "$exists": true } })
+            .confirm_delete_many(true);                                          // 显式确认
+     */
+    pub fn safe_delete(mut self, enabled: bool) -> Self {
+        self.safe_delete = enabled;
         self
     }
 
-    pub fn add_to_set(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::AddToSet(doc));
+    // 确认最后加入的 delete_many
+    pub fn confirm_delete_many(&mut self, force: bool) -> &mut Self {
+        // 确认不算新的操作，之后的 collation/hint 仍作用于刚加入的删除
+        let last_delete = self.last_delete;
+        self.commit_context();
+        self.last_delete = last_delete;
+        if let Some(index) = self.operations.iter().rposition(|op| matches!(op, WriteOperation::DeleteMany { .. })) {
+            if force {
+                if !self.confirmed_deletes.contains(&index) {
+                    self.confirmed_deletes.push(index);
+                }
+            } else {
+                self.confirmed_deletes.retain(|confirmed| *confirmed != index);
+            }
         }
         self
     }
 
-    pub fn add_to_set_each(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::AddToSetEach(doc));
-        }
-        self
+    fn is_selective_filter(filter: &Document) -> bool {
+        filter.iter().any(|(k, v)| match k.as_str() {
+            "$and" => match v {
+                Bson::Array(clauses) => clauses.iter().any(|clause| {
+                    matches!(clause, Bson::Document(clause) if Self::is_selective_filter(clause))
+                }),
+                _ => false,
+            },
+            _ if k.starts_with('$') => false,
+            _ => Self::is_selective_value(v),
+        })
     }
 
-    pub fn inc(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::Inc(doc));
+    fn is_selective_value(value: &Bson) -> bool {
+        match value {
+            // 操作符条件只看 $eq/$in/$regex，$exists/$ne/$gt 等单独出现时不缩小到具体文档
+            Bson::Document(condition) if condition.keys().next().map(|op| op.starts_with('$')).unwrap_or(false) => {
+                condition.get("$eq").map(Self::is_selective_value).unwrap_or(false)
+                    || matches!(condition.get("$in"), Some(Bson::Array(values)) if values.iter().all(Self::is_selective_value))
+                    || match condition.get("$regex") {
+                        Some(Bson::String(pattern)) => !Self::matches_all(pattern),
+                        Some(Bson::RegularExpression(regex)) => !Self::matches_all(&regex.pattern),
+                        _ => false,
+                    }
+            }
+            Bson::Null => false,
+            Bson::RegularExpression(regex) => !Self::matches_all(&regex.pattern),
+            _ => true,
         }
-        self
     }
 
-    pub fn mul(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::Mul(doc));
-        }
-        self
+    // 去掉锚点后为空或只有 .* / .+ 的正则匹配（几乎）所有字符串
+    fn matches_all(pattern: &str) -> bool {
+        let body = pattern.trim_start_matches('^').trim_end_matches('$');
+        matches!(body, "" | ".*" | ".+")
     }
 
-    pub fn min(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::Min(doc));
-        }
-        self
+    // 找出第一个未确认且不具选择性的 DeleteMany
+    fn find_unsafe_delete_many(&self) -> Option<usize> {
+        self.operations.iter().enumerate().find_map(|(index, operation)| match operation {
+            WriteOperation::DeleteMany { filter, .. }
+                if !self.confirmed_deletes.contains(&index) && !Self::is_selective_filter(filter) => Some(index),
+            _ => None,
+        })
     }
 
-    pub fn max(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::Max(doc));
-        }
+    pub fn on_empty_filter(mut self, policy: EmptyFilterPolicy) -> Self {
+        self.empty_filter_policy = policy;
         self
     }
 
-    pub fn rename(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::Rename(doc));
-        }
+    // 关闭自动合并：每次 set/inc 等调用都作为独立的 UpdateType 按调用顺序保存，execute 时从左到右折叠
+    pub fn disable_merge(mut self, disable: bool) -> Self {
+        self.merge_enabled = !disable;
         self
     }
 
-    pub fn current_date(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::CurrentDate(doc));
+    /*
+        从左到右折叠 UpdateType：相同操作符的字段合并到一起
+        同一种 UpdateType 重复写同一字段时（disable_merge 或合并被拒绝后会出现），按 merge 的规则折叠
+        （$inc 相加、$push 拼接 $each、$bit 合成等），无法折叠时返回错误，不会静默覆盖前一个值
+        不同的 UpdateType 输出同一个操作符且作用于同一字段时（如 Push 与 PushEach 都输出 $push），同样返回错误
+     */
+    fn build_update_document(updates: &[UpdateType]) -> Result<Document, Error> {
+        let mut update_doc = Document::new();
+        // (操作符, 字段, 该字段目前折叠出的单字段 UpdateType)
+        let mut writers: Vec<(String, String, UpdateType)> = Vec::new();
+        for update in updates {
+            let kind = std::mem::discriminant(update);
+            for (op, fields) in update.to_document() {
+                let fields = match fields {
+                    Bson::Document(fields) => fields,
+                    other => {
+                        update_doc.insert(op, other);
+                        continue;
+                    }
+                };
+                for (k, v) in fields {
+                    let single = update.with_fields(doc! { k.clone(): update.fields().get(&k).cloned().unwrap_or(Bson::Null) });
+                    let value = match writers.iter_mut().find(|(o, f, _)| o == &op && f == &k) {
+                        Some((_, _, previous)) if std::mem::discriminant(previous) != kind => {
+                            return Err(Error::custom(format!(
+                                "Conflicting {} updates on field '{}' from different update types", op, k
+                            )));
+                        }
+                        Some((_, _, previous)) => {
+                            let merged = previous.merge(&single).ok_or_else(|| {
+                                Error::custom(format!("Cannot combine {} updates on field '{}' into one update", op, k))
+                            })?;
+                            let value = merged.to_document().get_document(&op).ok().and_then(|d| d.get(&k).cloned()).unwrap_or(v);
+                            *previous = merged;
+                            value
+                        }
+                        None => {
+                            writers.push((op.clone(), k.clone(), single));
+                            v
+                        }
+                    };
+                    match update_doc.get_mut(&op) {
+                        Some(Bson::Document(existing)) => {
+                            existing.insert(k, value);
+                        }
+                        _ => {
+                            update_doc.insert(op.clone(), doc! { k: value });
+                        }
+                    }
+                }
+            }
+        }
+        Self::validate_update_paths(&update_doc)?;
+        Ok(update_doc)
+    }
+
+    /*
+        MongoDB 拒绝同一个更新中多个操作符作用于同一路径，或者一个路径是另一个的前缀：
+        { $set: { "a.b": 1 }, $inc: { "a": 1 } } 会在服务端报 conflict，这里提前返回错误
+        $rename 的目标字段也算作被修改的路径
+     */
+    fn validate_update_paths(update_doc: &Document) -> Result<(), Error> {
+        let mut paths: Vec<(&str, &str)> = Vec::new();
+        for (op, fields) in update_doc {
+            if let Bson::Document(fields) = fields {
+                for (field, value) in fields {
+                    paths.push((op, field));
+                    if op == "$rename" {
+                        if let Bson::String(target) = value {
+                            paths.push((op, target));
+                        }
+                    }
+                }
+            }
+        }
+        let overlaps = |a: &str, b: &str| {
+            a == b
+                || (a.len() > b.len() && a.starts_with(b) && a.as_bytes()[b.len()] == b'.')
+                || (b.len() > a.len() && b.starts_with(a) && b.as_bytes()[a.len()] == b'.')
+        };
+        for (i, (op1, path1)) in paths.iter().enumerate() {
+            for (op2, path2) in &paths[i + 1..] {
+                if overlaps(path1, path2) {
+                    return Err(Error::custom(format!(
+                        "Update would create a conflict: {} '{}' and {} '{}' target the same path", op1, path1, op2, path2
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 找出第一个 filter 为空的更新/删除操作
+    fn find_empty_filter(&self) -> Option<(usize, &'static str)> {
+        self.operations.iter().enumerate().find_map(|(index, operation)| match operation {
+            WriteOperation::UpdateOne { filter, .. } if filter.is_empty() => Some((index, "UpdateOne")),
+            WriteOperation::UpdateMany { filter, .. } if filter.is_empty() => Some((index, "UpdateMany")),
+            WriteOperation::DeleteOne { filter, .. } if filter.is_empty() => Some((index, "DeleteOne")),
+            WriteOperation::DeleteMany { filter, .. } if filter.is_empty() => Some((index, "DeleteMany")),
+            WriteOperation::ReplaceOne { filter, .. } if filter.is_empty() => Some((index, "ReplaceOne")),
+            _ => None,
+        })
+    }
+
+    fn commit_context(&mut self) {
+        self.last_delete = None;
+        if let Some(context) = self.current_context.take() {
+            if context.skip {
+                return;
+            }
+            let has_updates = !context.updates.is_empty();
+            let operation = if context.many {
+                WriteOperation::UpdateMany {
+                    filter: context.filter,
+                    updates: context.updates,
+                    upsert: context.is_upsert,
+                    options: context.options,
+                }
+            } else {
+                WriteOperation::UpdateOne {
+                    filter: context.filter,
+                    updates: context.updates,
+                    upsert: context.is_upsert,
+                    options: context.options,
+                }
+            };
+            match context.position {
+                // 写回原位置
+                Some(index) => self.operations[index] = operation,
+                None if has_updates => self.operations.push(operation),
+                None => {}
+            }
+        }
+    }
+
+
+
+    pub fn add_update(&mut self, filter: Document) -> &mut Self {
+        self.add_upsert(filter,false)
+    }
+
+    /*
+        在闭包中完成一个更新，闭包结束时立即提交，作用范围一目了然
+        builder.add_update_with(doc! { "id": 1 }, |b| {
+            b.set(doc! { "status": "paid" }).inc(doc! { "version": 1 });
+        });
+        闭包内不允许再调用 add_update_with，嵌套的调用不会生效，build_models/execute 返回错误
+     */
+    pub fn add_update_with(&mut self, filter: Document, f: impl FnOnce(&mut Self)) -> &mut Self {
+        if self.in_update_scope {
+            self.nested_update_scope = true;
+            return self;
+        }
+        self.in_update_scope = true;
+        self.add_update(filter);
+        f(self);
+        self.commit_context();
+        self.in_update_scope = false;
+        self
+    }
+
+    /*
+        把查询结构体转成 filter，规则见 struct_to_filter，返回的 filter 可以交给任意操作
+        base_filter 由各操作自己合并，这里不再合并
+        let filter = builder.filter_struct(&OrderQuery { status: Some("pending".into()), user_id: None })?;
+        builder.add_update_many(filter.clone()).set(doc! { "status": "paid" });
+        builder.delete_many(filter);
+     */
+    pub fn filter_struct<Q: Serialize>(&self, query: &Q) -> Result<Document, Error> {
+        struct_to_filter(query)
+    }
+
+    pub fn add_upsert(&mut self, filter: Document,upsert: bool) -> &mut Self {
+        self.open_update(filter, upsert, false)
+    }
+
+    /*
+        更新所有匹配的文档，set/inc 等的合并规则与 add_update 相同，同一 filter 的 UpdateMany 会合并
+        builder.add_update_many(doc! { "status": "pending", "expire_at": { "$lt": now } })
+            .set(doc! { "status": "expired" })
+            .inc(doc! { "version": 1 });
+     */
+    pub fn add_update_many(&mut self, filter: Document) -> &mut Self {
+        self.open_update(filter, false, true)
+    }
+
+    fn open_update(&mut self, filter: Document, upsert: bool, many: bool) -> &mut Self {
+        // 提交当前上下文
+        self.commit_context();
+        let filter = self.scoped_filter(filter);
+
+        // 查找是否存在相同filter的操作，找到则在原位置继续累加，保证 ordered 写入时的执行顺序不变
+        let position = self.find_update_position(&filter, many);
+
+        // 根据查找结果创建新的上下文
+        self.current_context = Some(match position.map(|index| &mut self.operations[index]) {
+            Some(WriteOperation::UpdateOne { updates, upsert, options, .. })
+            | Some(WriteOperation::UpdateMany { updates, upsert, options, .. }) => BatchUpdateContext {
+                filter,
+                updates: std::mem::take(updates),  // 使用已存在的updates
+                is_upsert: *upsert,
+                skip: false,
+                merge: self.merge_enabled,
+                position,
+                many,
+                options: std::mem::take(options),
+            },
+            _ => BatchUpdateContext {
+                filter,
+                updates: Vec::new(),
+                is_upsert: upsert,
+                skip: false,
+                merge: self.merge_enabled,
+                position: None,
+                many,
+                options: OperationOptions::default(),
+            }
+        });
+
+        self
+    }
+
+    fn find_update_position(&self, filter: &Document, many: bool) -> Option<usize> {
+        self.operations.iter()
+            .position(|op| match op {
+                WriteOperation::UpdateOne { filter: existing_filter, .. } if !many => {
+                    documents_equal(existing_filter, filter)
+                }
+                WriteOperation::UpdateMany { filter: existing_filter, .. } if many => {
+                    documents_equal(existing_filter, filter)
+                }
+                _ => false,
+            })
+    }
+
+    /*
+        合并另一个独立构建的批次，两者必须指向同一个集合
+        相同 filter 的更新按 add_update 的规则在原位置合并，其余操作和原始模型按顺序追加
+        other 中的 filter 已经应用过它自己的 base filter，这里不再重复限定
+        let mut batch = BatchUpdateBuilder::new(collection.clone());
+        batch.extend(build_order_updates(collection.clone(), &orders));
+        batch.extend(build_stock_updates(collection.clone(), &items));
+     */
+    pub fn extend(&mut self, mut other: BatchUpdateBuilder<T>) -> &mut Self {
+        assert_eq!(
+            self.collection.namespace(),
+            other.collection.namespace(),
+            "Cannot extend a batch that targets a different collection"
+        );
+        self.commit_context();
+        other.commit_context();
+
+        let merge = self.merge_enabled;
+        let mut raw_models = other.raw_models.into_iter().peekable();
+        for (index, operation) in other.operations.into_iter().enumerate() {
+            while let Some((_, model)) = raw_models.next_if(|(position, _)| *position <= index) {
+                self.raw_models.push((self.operations.len(), model));
+            }
+            let (filter, updates, upsert, options, many) = match operation {
+                WriteOperation::UpdateOne { filter, updates, upsert, options } => (filter, updates, upsert, options, false),
+                WriteOperation::UpdateMany { filter, updates, upsert, options } => (filter, updates, upsert, options, true),
+                operation => {
+                    if other.confirmed_deletes.contains(&index) {
+                        self.confirmed_deletes.push(self.operations.len());
+                    }
+                    self.operations.push(operation);
+                    continue;
+                }
+            };
+            match self.find_update_position(&filter, many).map(|position| &mut self.operations[position]) {
+                Some(WriteOperation::UpdateOne { updates: existing, upsert: existing_upsert, options: existing_options, .. })
+                | Some(WriteOperation::UpdateMany { updates: existing, upsert: existing_upsert, options: existing_options, .. }) => {
+                    let mut context = BatchUpdateContext {
+                        filter,
+                        updates: std::mem::take(existing),
+                        is_upsert: *existing_upsert || upsert,
+                        skip: false,
+                        merge,
+                        position: None,
+                        many,
+                        options: OperationOptions::default(),
+                    };
+                    for update in updates {
+                        context.add_update_type(update);
+                    }
+                    *existing = context.updates;
+                    *existing_upsert = context.is_upsert;
+                    existing_options.merge(options);
+                }
+                _ if many => self.operations.push(WriteOperation::UpdateMany { filter, updates, upsert, options }),
+                _ => self.operations.push(WriteOperation::UpdateOne { filter, updates, upsert, options }),
+            }
+        }
+        for (_, model) in raw_models {
+            self.raw_models.push((self.operations.len(), model));
+        }
+        self
+    }
+
+    /*
+        以确定的 key upsert：filter 为 { field: value }，同时在 $setOnInsert 中写入该 key，
+        保证插入的新文档带有预期的 key，与其他 set_on_insert 字段正常合并
+        builder.upsert_key("_id", order_id)
+            .set(doc! { "status": "paid" })
+            .set_on_insert(doc! { "created_by": "job" });
+     */
+    pub fn upsert_key(&mut self, field: &str, value: impl Into<Bson>) -> &mut Self {
+        let value = value.into();
+        self.add_upsert(doc! { field: value.clone() }, true)
+            .upsert(true)
+            .set_on_insert(doc! { field: value })
+    }
+
+    /*
+        按条件加入操作，避免在调用处分支：
+        builder.maybe_update(need_rename, doc! { "id": 1 })
+            .set(doc! { "name": "new" })   // need_rename 为 false 时被丢弃
+            .maybe_delete(expired, doc! { "id": 2 });
+     */
+    pub fn maybe_update(&mut self, condition: bool, filter: Document) -> &mut Self {
+        if condition {
+            return self.add_update(filter);
+        }
+        self.commit_context();
+        self.current_context = Some(BatchUpdateContext {
+            filter,
+            updates: Vec::new(),
+            is_upsert: false,
+            skip: true,
+            merge: self.merge_enabled,
+            position: None,
+            many: false,
+            options: OperationOptions::default(),
+        });
+        self
+    }
+
+    pub fn maybe_delete(&mut self, condition: bool, filter: Document) -> &mut Self {
+        if condition {
+            return self.delete(filter);
+        }
+        self.commit_context();
+        self
+    }
+
+    pub fn maybe_insert(&mut self, condition: bool, document: T) -> &mut Self {
+        if condition {
+            return self.insert(document);
+        }
+        self.commit_context();
+        self
+    }
+
+    /*
+        把预先构造好的一组 UpdateType 依次加入当前上下文，与逐个调用 set/inc 等方法的合并规则一致
+        let updates = vec![UpdateType::Set(doc! { "status": "paid" }), UpdateType::Inc(doc! { "version": 1 })];
+        builder.add_update(doc! { "id": 1 }).apply_updates(updates);
+     */
+    pub fn apply_updates(&mut self, updates: Vec<UpdateType>) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            for update in updates {
+                context.add_update_type(update);
+            }
+        }
+        self
+    }
+
+    /*
+        过滤位置更新 $[elem] 的 arrayFilters，作用于整个更新文档，所以挂在当前上下文上
+        builder.add_update(doc! { "_id": 1 })
+            .set(doc! { "items.$[elem].status": "shipped" })
+            .array_filters(vec![doc! { "elem.sku": "A1" }]);
+     */
+    pub fn array_filters(&mut self, filters: Vec<Document>) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.options.array_filters = Some(filters);
+        }
+        self
+    }
+
+    /*
+        排序规则：有当前更新上下文时作用于该更新，否则作用于紧挨着的上一个 delete/delete_many，
+        两者都没有时什么也不做（被跳过的 maybe_delete、build 之后都不会作用到更早的删除上）
+        let collation = Collation::builder().locale("en").strength(CollationStrength::Secondary).build();
+        builder.add_update(doc! { "name": "alice" }).set(doc! { "vip": true }).collation(collation.clone())
+            .delete(doc! { "name": "BOB" }).collation(collation);
+     */
+    pub fn collation(&mut self, collation: Collation) -> &mut Self {
+        if let Some(options) = self.current_options() {
+            options.collation = Some(collation);
+        }
+        self
+    }
+
+    /*
+        索引提示，规则同 collation：作用于当前更新或上一个删除操作
+        字符串为索引名，文档为索引键，其他类型被忽略
+        builder.add_update(doc! { "user_id": 1, "status": "pending" })
+            .set(doc! { "status": "done" })
+            .hint(Bson::from("user_id_1_status_1"))
+            .delete_many(doc! { "expire_at": { "$lt": now } })
+            .hint(Bson::Document(doc! { "expire_at": 1 }));
+     */
+    pub fn hint(&mut self, hint: Bson) -> &mut Self {
+        let hint = match hint {
+            Bson::String(name) => Hint::Name(name),
+            Bson::Document(keys) => Hint::Keys(keys),
+            _ => return self,
+        };
+        if let Some(options) = self.current_options() {
+            options.hint = Some(hint);
+        }
+        self
+    }
+
+    // 当前更新上下文的选项；没有上下文时取上一个调用刚加入的删除操作的选项
+    fn current_options(&mut self) -> Option<&mut OperationOptions> {
+        if let Some(context) = &mut self.current_context {
+            return Some(&mut context.options);
+        }
+        match self.last_delete.and_then(|index| self.operations.get_mut(index)) {
+            Some(WriteOperation::DeleteOne { options, .. }) | Some(WriteOperation::DeleteMany { options, .. }) => Some(options),
+            _ => None,
+        }
+    }
+
+    pub fn set(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::Set(doc));
+        }
+        self
+    }
+
+    pub fn unset(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::Unset(doc));
+        }
+        self
+    }
+
+    pub fn set_on_insert(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::SetOnInsert(doc));
+        }
+        self
+    }
+
+    pub fn push(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::Push(doc));
+        }
+        self
+    }
+
+    pub fn push_each(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::PushEach(doc));
+        }
+        self
+    }
+
+    pub fn pull(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::Pull(doc));
+        }
+        self
+    }
+
+    pub fn pull_all(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::PullAll(doc));
+        }
+        self
+    }
+
+    pub fn pop(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::Pop(doc));
+        }
+        self
+    }
+
+    pub fn add_to_set(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::AddToSet(doc));
         }
         self
     }
 
-    pub fn bit(&mut self, doc: Document) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.add_update_type(UpdateType::Bit(doc));
-        }
-        self
-    }
+    pub fn add_to_set_each(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::AddToSetEach(doc));
+        }
+        self
+    }
+
+    pub fn inc(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::Inc(doc));
+        }
+        self
+    }
+
+    pub fn mul(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::Mul(doc));
+        }
+        self
+    }
+
+    pub fn min(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::Min(doc));
+        }
+        self
+    }
+
+    pub fn max(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::Max(doc));
+        }
+        self
+    }
+
+    pub fn rename(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::Rename(doc));
+        }
+        self
+    }
+
+    pub fn current_date(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::CurrentDate(doc));
+        }
+        self
+    }
+
+    pub fn bit(&mut self, doc: Document) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.add_update_type(UpdateType::Bit(doc));
+        }
+        self
+    }
+
+    pub fn upsert(&mut self, upsert: bool) -> &mut Self {
+        if let Some(context) = &mut self.current_context {
+            context.is_upsert = upsert;
+        }
+        self
+    }
+
+    pub fn delete(&mut self, filter: Document) -> &mut Self {
+        self.commit_context();
+        let filter = self.scoped_filter(filter);
+        self.operations.push(WriteOperation::DeleteOne { filter, options: OperationOptions::default() });
+        self.last_delete = Some(self.operations.len() - 1);
+        self
+    }
+
+    pub fn delete_many(&mut self, filter: Document) -> &mut Self {
+        self.commit_context();
+        let filter = self.scoped_filter(filter);
+        self.operations.push(WriteOperation::DeleteMany { filter, options: OperationOptions::default() });
+        self.last_delete = Some(self.operations.len() - 1);
+        self
+    }
+
+    pub fn insert(&mut self, document: T) -> &mut Self {
+        self.commit_context();
+        self.operations.push(WriteOperation::InsertOne { document });
+        self
+    }
+
+    /*
+        批量插入：每个文档一个 InsertOne，只提交一次上下文，空 Vec 什么也不做
+        builder.insert_many(orders);
+     */
+    pub fn insert_many(&mut self, documents: Vec<T>) -> &mut Self {
+        if documents.is_empty() {
+            self.last_delete = None;
+            return self;
+        }
+        self.commit_context();
+        self.operations.reserve(documents.len());
+        self.operations.extend(documents.into_iter().map(|document| WriteOperation::InsertOne { document }));
+        self
+    }
+
+    /*
+        整文档替换，filter 为空时与更新一样被跳过，避免误替换任意一条文档
+        builder.replace(doc! { "id": 1 }, order)
+            .upsert_replace(doc! { "id": 2 }, other_order);
+     */
+    pub fn replace(&mut self, filter: Document, document: T) -> &mut Self {
+        self.push_replace(filter, document, false)
+    }
+
+    // 没有匹配的文档时插入 replacement
+    pub fn upsert_replace(&mut self, filter: Document, document: T) -> &mut Self {
+        self.push_replace(filter, document, true)
+    }
+
+    fn push_replace(&mut self, filter: Document, replacement: T, upsert: bool) -> &mut Self {
+        self.commit_context();
+        let filter = self.scoped_filter(filter);
+        self.operations.push(WriteOperation::ReplaceOne { filter, replacement, upsert });
+        self
+    }
+
+    /*
+        直接加入一个构建好的 WriteModel，用于 builder 尚未支持的操作，执行顺序与前后操作保持一致
+        builder.add_update(doc! { "id": 1 }).set(doc! { "name": "a" })
+            .add_raw_model(WriteModel::UpdateMany(model))
+            .delete(doc! { "id": 2 });
+     */
+    pub fn add_raw_model(&mut self, model: WriteModel) -> &mut Self {
+        self.commit_context();
+        self.raw_models.push((self.operations.len(), model));
+        self
+    }
+
+    /*
+        按 key_field 批量 upsert：每个文档生成 { key_field: value } 的 filter 和整文档的 $set
+        builder.upsert_many("sku", products)?;
+        _id 不能出现在 $set 中（不可修改），非 _id 作为 key 时放到 $setOnInsert
+        任何一个文档缺少 key_field 时返回错误，且不会加入任何操作
+     */
+    pub fn upsert_many(&mut self, key_field: &str, docs: Vec<T>) -> Result<&mut Self, Error> {
+        let mut prepared = Vec::with_capacity(docs.len());
+        for (index, document) in docs.iter().enumerate() {
+            let mut document = to_document(document)?;
+            let key = document.get(key_field).cloned().ok_or_else(|| {
+                Error::custom(format!("Document {} is missing key field '{}'", index, key_field))
+            })?;
+            let id = document.remove("_id");
+            prepared.push((key, id, document));
+        }
+
+        for (key, id, document) in prepared {
+            self.add_upsert(doc! { key_field: key }, true).set(document);
+            if let (Some(id), false) = (id, key_field == "_id") {
+                self.set_on_insert(doc! { "_id": id });
+            }
+        }
+        self.commit_context();
+        Ok(self)
+    }
+
+    pub fn build(&mut self) -> &mut Self {
+        self.commit_context();
+        self
+    }
+ 
+    fn build_write_model(&self, operation: &WriteOperation<T>) -> Result<Option<WriteModel>, Error> {
+        let model = match operation {
+            WriteOperation::UpdateOne { filter, updates, upsert, options } => {
+                // 如果 filter为{} 则不进行更新，因为这是一个破坏性极大的操作，会把整个表的数据都更新，这种操作要禁止
+                if !filter.is_empty() && !updates.is_empty() {
+                    let mut update_doc = Self::build_update_document(updates)?;
+                    if *upsert {
+                        self.stamp_upsert(&mut update_doc);
+                    }
+                    self.stamp_updated(&mut update_doc);
+                    Self::validate_update_paths(&update_doc)?;
+
+                    Some(UpdateOneModel::builder()
+                        .namespace(self.collection.namespace())
+                        .filter(filter.clone())
+                        .update(UpdateModifications::Document(update_doc))
+                        .upsert(Some(*upsert))
+                        .array_filters(options.array_filters.clone())
+                        .collation(options.collation.clone())
+                        .hint(options.hint.clone())
+                        .build()
+                        .into())
+                } else {
+                    None
+                }
+            },
+            WriteOperation::UpdateMany { filter, updates, upsert, options } => {
+                // 与 UpdateOne 一样，空 filter 会更新整个集合，直接跳过
+                if !filter.is_empty() && !updates.is_empty() {
+                    let mut update_doc = Self::build_update_document(updates)?;
+                    if *upsert {
+                        self.stamp_upsert(&mut update_doc);
+                    }
+                    self.stamp_updated(&mut update_doc);
+                    Self::validate_update_paths(&update_doc)?;
+
+                    Some(UpdateManyModel::builder()
+                        .namespace(self.collection.namespace())
+                        .filter(filter.clone())
+                        .update(UpdateModifications::Document(update_doc))
+                        .upsert(Some(*upsert))
+                        .array_filters(options.array_filters.clone())
+                        .collation(options.collation.clone())
+                        .hint(options.hint.clone())
+                        .build()
+                        .into())
+                } else {
+                    None
+                }
+            },
+            WriteOperation::DeleteOne { filter, options } => {
+                if !filter.is_empty() {
+                    Some(DeleteOneModel::builder()
+                        .namespace(self.collection.namespace())
+                        .filter(filter.clone())
+                        .collation(options.collation.clone())
+                        .hint(options.hint.clone())
+                        .build()
+                        .into())
+                } else {
+                    None
+                }
+            },
+            WriteOperation::DeleteMany { filter, options } => {
+                if !filter.is_empty() {
+                    Some(DeleteManyModel::builder()
+                        .namespace(self.collection.namespace())
+                        .filter(filter.clone())
+                        .collation(options.collation.clone())
+                        .hint(options.hint.clone())
+                        .build()
+                        .into())
+                } else {
+                    None
+                }
+            },
+            WriteOperation::InsertOne { document } => {
+                // 无法序列化为文档时返回错误，而不是 panic
+                let mut document = to_document(document)?;
+                if let Some(field) = &self.timestamp_field {
+                    if !document.contains_key(field) {
+                        document.insert(field.as_str(), DateTime::now());
+                    }
+                }
+                Some(InsertOneModel::builder()
+                    .namespace(self.collection.namespace())
+                    .document(document)
+                    .build()
+                    .into())
+            },
+            WriteOperation::ReplaceOne { filter, replacement, upsert } => {
+                if !filter.is_empty() {
+                    Some(ReplaceOneModel::builder()
+                        .namespace(self.collection.namespace())
+                        .filter(filter.clone())
+                        .replacement(to_document(replacement)?)
+                        .upsert(Some(*upsert))
+                        .build()
+                        .into())
+                } else {
+                    None
+                }
+            },
+        };
+        Ok(model)
+    }
+
+    // upsert 插入时通过 $setOnInsert 写入创建时间，已在 $set/$setOnInsert 中显式设置的不覆盖
+    fn stamp_upsert(&self, update_doc: &mut Document) {
+        let field = match &self.timestamp_field {
+            Some(field) => field.as_str(),
+            None => return,
+        };
+        let already_set = ["$set", "$setOnInsert"].iter().any(|op| {
+            update_doc.get_document(op).map(|d| d.contains_key(field)).unwrap_or(false)
+        }) || Self::renamed_to(update_doc, field);
+        if already_set {
+            return;
+        }
+        match update_doc.get_mut("$setOnInsert") {
+            Some(Bson::Document(set_on_insert)) => {
+                set_on_insert.insert(field, DateTime::now());
+            }
+            _ => {
+                update_doc.insert("$setOnInsert", doc! { field: DateTime::now() });
+            }
+        }
+    }
+
+    // $rename 的目标是 field 时，该字段已经由重命名写入
+    fn renamed_to(update_doc: &Document, field: &str) -> bool {
+        update_doc.get_document("$rename")
+            .map(|renames| renames.values().any(|target| target.as_str() == Some(field)))
+            .unwrap_or(false)
+    }
+
+    // 更新时通过 $currentDate 写入修改时间，用户已经写了该字段的不覆盖
+    fn stamp_updated(&self, update_doc: &mut Document) {
+        let field = match &self.updated_field {
+            Some(field) => field.as_str(),
+            None => return,
+        };
+        let already_set = ["$currentDate", "$set", "$unset"].iter().any(|op| {
+            update_doc.get_document(op).map(|d| d.contains_key(field)).unwrap_or(false)
+        }) || Self::renamed_to(update_doc, field);
+        if already_set {
+            return;
+        }
+        match update_doc.get_mut("$currentDate") {
+            Some(Bson::Document(current_date)) => {
+                current_date.insert(field, true);
+            }
+            _ => {
+                update_doc.insert("$currentDate", doc! { field: true });
+            }
+        }
+    }
+
+    /*
+        提交上下文并构建最终发送的 write_models，不执行写入，execute 内部也走这里
+        空 filter 的操作已被丢弃、相同 filter 的更新已经合并，可用于日志或在测试中断言
+        let models = builder.build_models()?;
+        println!("will send {} write models", models.len());
+        on_empty_filter(Error)、safe_delete 校验失败时返回错误
+     */
+    pub fn build_models(&mut self) -> Result<Vec<WriteModel>, Error> {
+        if self.nested_update_scope {
+            return Err(Error::custom("add_update_with cannot be nested"));
+        }
+        self.build();
+
+        if self.empty_filter_policy == EmptyFilterPolicy::Error {
+            if let Some((index, kind)) = self.find_empty_filter() {
+                return Err(Error::custom(format!("Operation {} ({}) has an empty filter", index, kind)));
+            }
+        }
+        if self.safe_delete {
+            if let Some(index) = self.find_unsafe_delete_many() {
+                return Err(Error::custom(format!(
+                    "Operation {} (DeleteMany) has a non-selective filter, call confirm_delete_many(true) to force it", index
+                )));
+            }
+        }
+        // 原始 WriteModel 按加入时的位置插入到对应操作之前
+        let mut raw_models = self.raw_models.iter().peekable();
+        let mut write_models: Vec<WriteModel> = Vec::new();
+        let mut model_sources: Vec<Option<usize>> = Vec::new();
+        for (index, operation) in self.operations.iter().enumerate() {
+            while let Some((_, model)) = raw_models.next_if(|(position, _)| *position <= index) {
+                write_models.push(model.clone());
+                model_sources.push(None);
+            }
+            if let Some(model) = self.build_write_model(operation)? {
+                write_models.push(model);
+                model_sources.push(Some(index));
+            }
+        }
+        for (_, model) in raw_models {
+            write_models.push(model.clone());
+            model_sources.push(None);
+        }
+        self.model_sources = model_sources;
+        Ok(write_models)
+    }
+
+    // execute 前打印所有操作和最终发送的 write_models
+    fn log_operations(&self, write_models: &[WriteModel]) {
+        // Print all database operations before executing
+        println!("Executing database operations:");
+        for (i, op) in self.operations.iter().enumerate() {
+            match op {
+                WriteOperation::UpdateOne { filter, updates, upsert, .. } => {
+                    println!("Operation {}: UpdateOne", i);
+                    println!("  Filter: {:?}", filter);
+                    println!("  Updates: {:?}", updates);
+                    println!("  Upsert: {:?}", upsert);
+                },
+                WriteOperation::UpdateMany { filter, updates, upsert, .. } => {
+                    println!("Operation {}: UpdateMany", i);
+                    println!("  Filter: {:?}", filter);
+                    println!("  Updates: {:?}", updates);
+                    println!("  Upsert: {:?}", upsert);
+                },
+                WriteOperation::DeleteOne { filter, .. } => {
+                    println!("Operation {}: DeleteOne", i);
+                    println!("  Filter: {:?}", filter);
+                },
+                WriteOperation::DeleteMany { filter, .. } => {
+                    println!("Operation {}: DeleteMany", i);
+                    println!("  Filter: {:?}", filter);
+                },
+                WriteOperation::InsertOne { document } => {
+                    println!("Operation {}: InsertOne", i);
+                    //println!("  Document: {:?}", document);
+                },
+                WriteOperation::ReplaceOne { filter, upsert, .. } => {
+                    println!("Operation {}: ReplaceOne", i);
+                    println!("  Filter: {:?}", filter);
+                    println!("  Upsert: {:?}", upsert);
+                }
+            }
+        }
+        println!("write_models>>>>{:?}",write_models);
+    }
+
+    /*
+        把批量写入错误中的下标映射回原始操作
+        空过滤条件被跳过、原始模型插入等都会让 write_models 的下标与 add_update 的调用顺序错位，
+        这里用上次 execute 记录的位置表还原出失败的那一条操作
+        if let Err(err) = builder.execute().await {
+            for failed in builder.failed_operations(&err) {
+                println!("{} failed: {} {:?}", failed.model_index, failed.message, failed.operation);
+            }
+        }
+     */
+    pub fn failed_operations(&self, err: &Error) -> Vec<FailedOperation<T>>
+    where
+        T: Clone,
+    {
+        let write_errors = match err.kind.as_ref() {
+            ErrorKind::ClientBulkWrite(bulk_error) => &bulk_error.write_errors,
+            _ => return Vec::new(),
+        };
+        let mut failed: Vec<FailedOperation<T>> = write_errors
+            .iter()
+            .map(|(model_index, write_error)| {
+                let operation_index = self.model_sources.get(*model_index).copied().flatten();
+                FailedOperation {
+                    model_index: *model_index,
+                    operation_index,
+                    operation: operation_index.and_then(|index| self.operations.get(index).cloned()),
+                    code: write_error.code,
+                    message: write_error.message.clone(),
+                }
+            })
+            .collect();
+        failed.sort_by_key(|item| item.model_index);
+        failed
+    }
+
+    pub async fn execute(&mut self) -> Result<SummaryBulkWriteResult, Error> {
+        let write_models = self.build_models()?;
+        self.log_operations(&write_models);
+        if write_models.is_empty(){ 
+            return Ok(SummaryBulkWriteResult::default());
+        }
+        if let Some(key) = self.idempotency_key.clone() {
+            return self.execute_idempotent(&key, write_models).await;
+        }
+        self.collection.client().bulk_write(write_models).with_options(self.bulk_write_options()).await
+    }
+
+    /*
+        在调用方的会话中执行，会话已开启事务时本次写入成为事务的一部分
+        let mut session = client.start_session().await?;
+        session.start_transaction().await?;
+        orders.execute_with_session(&mut session).await?;
+        stocks.execute_with_session(&mut session).await?;
+        session.commit_transaction().await?;
+        注意：事务中任何一个写入失败都会导致整个事务无法提交，ordered(false) 只决定同一批次中失败后是否继续尝试后续写入，
+        不会让已失败的事务部分提交；idempotency_key 在这里不生效，幂等由调用方的事务负责
+     */
+    pub async fn execute_with_session(&mut self, session: &mut ClientSession) -> Result<SummaryBulkWriteResult, Error> {
+        let write_models = self.build_models()?;
+        if write_models.is_empty() {
+            return Ok(SummaryBulkWriteResult::default());
+        }
+        self.collection.client().bulk_write(write_models).with_options(self.bulk_write_options()).session(session).await
+    }
+
+    /*
+        按 chunk_size 分成多次 bulk_write 顺序执行，全部成功时返回各批次计数的累加
+        ordered 为 true 时遇到失败的批次立即停止；为 false 时继续执行后续批次
+        任何批次失败都返回 ChunkedWriteError，其中带有已经成功写入的批次计数和各失败批次的错误
+        match builder.execute_chunked(1000).await {
+            Ok(summary) => ...,
+            Err(e) => println!("written before failure: {:?}, errors: {:?}", e.summary, e.errors),
+        }
+        注意：错误中的下标是相对所在批次的，failed_operations 只适用于 execute；失败批次中部分写入的计数见错误的 partial_result
+        幂等标记无法跨多个批次保证，设置了 idempotency_key 时返回错误，请使用 execute
+     */
+    pub async fn execute_chunked(&mut self, chunk_size: usize) -> Result<SummaryBulkWriteResult, ChunkedWriteError> {
+        if chunk_size == 0 {
+            return Err(Error::custom("Chunk size must be greater than 0").into());
+        }
+        if self.idempotency_key.is_some() {
+            return Err(Error::custom("execute_chunked does not support idempotency_key, use execute instead").into());
+        }
+        let write_models = self.build_models()?;
+        let client = self.collection.client();
+        let mut summary = SummaryBulkWriteResult::default();
+        let mut errors = Vec::new();
+        for chunk in write_models.chunks(chunk_size) {
+            match client.bulk_write(chunk.to_vec()).with_options(self.bulk_write_options()).await {
+                Ok(result) => Self::accumulate_summary(&mut summary, &result),
+                Err(e) => {
+                    errors.push(e);
+                    if self.ordered {
+                        break;
+                    }
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(summary)
+        } else {
+            Err(ChunkedWriteError { summary, errors })
+        }
+    }
+
+    fn accumulate_summary(total: &mut SummaryBulkWriteResult, chunk: &SummaryBulkWriteResult) {
+        total.inserted_count += chunk.inserted_count;
+        total.matched_count += chunk.matched_count;
+        total.modified_count += chunk.modified_count;
+        total.upserted_count += chunk.upserted_count;
+        total.deleted_count += chunk.deleted_count;
+    }
+
+    /*
+        执行并返回简化的计数，失败时错误中带有出错的原始操作（filter/更新）
+        match builder.run().await {
+            Ok(summary) => println!("modified {} / upserted {}", summary.modified, summary.upserted),
+            Err(e) => for failed in &e.failed {
+                println!("{} failed: {} {:?}", failed.model_index, failed.message, failed.operation);
+            },
+        }
+     */
+    pub async fn run(&mut self) -> Result<BulkSummary, RunError<T>>
+    where
+        T: Clone,
+    {
+        match self.execute().await {
+            Ok(summary) => Ok(BulkSummary::from(&summary)),
+            Err(error) => {
+                let failed = self.failed_operations(&error);
+                Err(RunError { error, failed })
+            }
+        }
+    }
+
+    /*
+        幂等执行：在同库的 IDEMPOTENCY_COLLECTION 中以 key 作为 _id 记录执行结果
+        同一个 key 再次执行时直接返回上次的结果，不再写入
+        标记写入与批量写入在同一个事务中完成，需要副本集/分片集群
+        let mut builder = BatchUpdateBuilder::new(collection).idempotency_key(format!("job:{}", job_id));
+     */
+    pub fn idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+
+    fn summary_from_marker(marker: &Document) -> SummaryBulkWriteResult {
+        let mut summary = SummaryBulkWriteResult::default();
+        summary.inserted_count = marker.get_i64("inserted_count").unwrap_or(0);
+        summary.matched_count = marker.get_i64("matched_count").unwrap_or(0);
+        summary.modified_count = marker.get_i64("modified_count").unwrap_or(0);
+        summary.upserted_count = marker.get_i64("upserted_count").unwrap_or(0);
+        summary.deleted_count = marker.get_i64("deleted_count").unwrap_or(0);
+        summary
+    }
+
+    async fn execute_idempotent(&self, key: &str, write_models: Vec<WriteModel>) -> Result<SummaryBulkWriteResult, Error> {
+        let client = self.collection.client();
+        let markers = client
+            .database(&self.collection.namespace().db)
+            .collection::<Document>(IDEMPOTENCY_COLLECTION);
+
+        let mut session = client.start_session().await?;
+        session.start_transaction().await?;
+        // 在事务中检查标记，并发执行时与下面的标记写入冲突，不会两个都通过
+        let marker = match markers.find_one(doc! { "_id": key }).session(&mut session).await {
+            Ok(marker) => marker,
+            Err(e) => {
+                session.abort_transaction().await?;
+                return Err(e);
+            }
+        };
+        if let Some(marker) = marker {
+            session.abort_transaction().await?;
+            return Ok(Self::summary_from_marker(&marker));
+        }
+        let result = client.bulk_write(write_models).with_options(self.bulk_write_options()).session(&mut session).await;
+        let summary = match result {
+            Ok(summary) => summary,
+            Err(e) => {
+                session.abort_transaction().await?;
+                return Err(e);
+            }
+        };
+        // 并发执行同一个 key 时，这里的 _id 冲突会让事务整体失败
+        if let Err(e) = markers.insert_one(doc! {
+            "_id": key,
+            "inserted_count": summary.inserted_count,
+            "matched_count": summary.matched_count,
+            "modified_count": summary.modified_count,
+            "upserted_count": summary.upserted_count,
+            "deleted_count": summary.deleted_count,
+            "created_at": mongodb::bson::DateTime::now(),
+        }).session(&mut session).await {
+            session.abort_transaction().await?;
+            return Err(e);
+        }
+        session.commit_transaction().await?;
+        Ok(summary)
+    }
+
+    // 单个操作渲染成文档，用于日志/审计
+    fn operation_document(operation: &WriteOperation<T>) -> Result<Document, Error> {
+        let document = match operation {
+            WriteOperation::UpdateOne { filter, updates, upsert, options } => {
+                let mut document = doc! {
+                    "op": "updateOne",
+                    "filter": filter,
+                    "update": Self::build_update_document(updates)?,
+                    "upsert": upsert,
+                };
+                Self::append_options(&mut document, options);
+                document
+            },
+            WriteOperation::UpdateMany { filter, updates, upsert, options } => {
+                let mut document = doc! {
+                    "op": "updateMany",
+                    "filter": filter,
+                    "update": Self::build_update_document(updates)?,
+                    "upsert": upsert,
+                };
+                Self::append_options(&mut document, options);
+                document
+            },
+            WriteOperation::DeleteOne { filter, options } => {
+                let mut document = doc! { "op": "deleteOne", "filter": filter };
+                Self::append_options(&mut document, options);
+                document
+            },
+            WriteOperation::DeleteMany { filter, options } => {
+                let mut document = doc! { "op": "deleteMany", "filter": filter };
+                Self::append_options(&mut document, options);
+                document
+            },
+            WriteOperation::InsertOne { document } => doc! {
+                "op": "insertOne",
+                "document": to_document(document)?,
+            },
+            WriteOperation::ReplaceOne { filter, replacement, upsert } => doc! {
+                "op": "replaceOne",
+                "filter": filter,
+                "replacement": to_document(replacement)?,
+                "upsert": upsert,
+            },
+        };
+        Ok(document)
+    }
+
+    fn append_options(document: &mut Document, options: &OperationOptions) {
+        if let Some(array_filters) = &options.array_filters {
+            document.insert("arrayFilters", array_filters.clone());
+        }
+        if let Some(collation) = &options.collation {
+            if let Ok(collation) = mongodb::bson::to_bson(collation) {
+                document.insert("collation", collation);
+            }
+        }
+        match &options.hint {
+            Some(Hint::Keys(keys)) => { document.insert("hint", keys.clone()); }
+            Some(Hint::Name(name)) => { document.insert("hint", name.clone()); }
+            _ => {}
+        }
+    }
+
+    /*
+        把已提交的操作输出为 MongoDB 扩展 JSON 数组（canonical 格式），ObjectId/Binary/数值类型都可以原样还原
+        let json = builder.to_extended_json()?;
+        // [{"op":"updateOne","filter":{"id":{"$numberInt":"1"}},"update":{"$set":{...}},"upsert":false}, ...]
+        注意：add_raw_model 加入的原始模型不包含在内
+     */
+    pub fn to_extended_json(&mut self) -> Result<String, Error> {
+        self.build();
+        let documents = self.operations.iter()
+            .map(|operation| Self::operation_document(operation).map(Bson::Document))
+            .collect::<Result<Vec<Bson>, Error>>()?;
+        serde_json::to_string(&Bson::Array(documents).into_canonical_extjson())
+            .map_err(|e| Error::custom(e.to_string()))
+    }
+
+    /*
+        执行前预估每个更新/删除操作匹配的文档数量，不做任何写入
+        let estimates = builder.estimate_affected().await?;
+        for (filter, count) in estimates {
+            println!("{:?} => {}", filter, count);
+        }
+        注意：返回的是 filter 匹配的数量，UpdateOne/DeleteOne 实际最多只影响 1 条
+     */
+    pub async fn estimate_affected(&mut self) -> Result<Vec<(Document, u64)>, Error> {
+        self.build();
+
+        let mut estimates = Vec::new();
+        for operation in &self.operations {
+            let filter = match operation {
+                WriteOperation::UpdateOne { filter, .. }
+                | WriteOperation::UpdateMany { filter, .. }
+                | WriteOperation::DeleteOne { filter, .. }
+                | WriteOperation::DeleteMany { filter, .. }
+                | WriteOperation::ReplaceOne { filter, .. } => filter,
+                WriteOperation::InsertOne { .. } => continue,
+            };
+            let count = self.collection.count_documents(filter.clone()).await?;
+            estimates.push((filter.clone(), count));
+        }
+        Ok(estimates)
+    }
+
+    // 临时性错误（网络抖动、主节点切换）才重试，重复键、校验失败等错误直接返回
+    fn is_retryable(err: &Error) -> bool {
+        if err.contains_label("TransientTransactionError") || err.contains_label("RetryableWriteError") {
+            return true;
+        }
+        matches!(*err.kind, ErrorKind::Io(_) | ErrorKind::ConnectionPoolCleared { .. })
+    }
+
+    /*
+        带指数退避的重试：每次重试都会重新走 execute() 构建 write_models
+        builder.run_with_retry(3, Duration::from_millis(200)).await?;
+        // 失败后依次等待 200ms、400ms 再重试
+        注意：ordered(false) 或部分成功的批次重试时，$inc 等非幂等操作可能被重复应用
+     */
+    pub async fn run_with_retry(&mut self, attempts: u32, backoff: Duration) -> Result<SummaryBulkWriteResult, Error> {
+        let mut delay = backoff;
+        let mut attempt = 1;
+        loop {
+            match self.execute().await {
+                Ok(result) => return Ok(result),
+                Err(e) if attempt < attempts && Self::is_retryable(&e) => {
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+  
+}
+
+impl BatchUpdateContext{
+    fn add_update_type(&mut self, new_update: UpdateType) {
+        if !self.merge {
+            self.updates.push(new_update);
+            return;
+        }
+
+        // 尝试合并相同类型的操作
+        let mut merged = false;
+        for existing_update in &mut self.updates {
+            if let Some(merged_update) = existing_update.merge(&new_update) {
+                *existing_update = merged_update;
+                merged = true;
+                break;
+            }
+        }
+        
+        if !merged {
+            self.updates.push(new_update);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::{Client, bson::doc};
+    use crate::models::order::Order;
+
+    async fn get_test_collection() -> Collection<Order> {
+        let client = Client::with_uri_str("mongodb://localhost:27017").await.unwrap();
+        client.database("test").collection("test_collection")
+    }
+
+    #[tokio::test]
+    async fn test_different_update_types_same_filter() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+        let filter = doc! { "id": 1 };
+
+        builder.add_update(filter.clone())
+                        .set(doc! { "name": "test1" })
+                        .inc(doc! { "count": 1 });
+                        //.build();
+
+        assert_eq!(builder.operations.len(), 1);
+        if let WriteOperation::UpdateOne { updates, .. } = &builder.operations[0] {
+            assert_eq!(updates.len(), 2);
+            match (&updates[0], &updates[1]) {
+                (UpdateType::Set(set_doc), UpdateType::Inc(inc_doc)) => {
+                    assert_eq!(set_doc.get("name").unwrap().as_str().unwrap(), "test1");
+                    assert_eq!(inc_doc.get("count").unwrap().as_i32().unwrap(), 1);
+                }
+                _ => panic!("Unexpected update types")
+            }
+        } else {
+            panic!("Expected UpdateOne operation");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merge_same_update_type_and_filter() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+        let filter = doc! { "id": 1 };
+
+        builder.add_update(filter.clone())
+            .set(doc! { "name": "test1" })
+            .set(doc! { "age": 20 });
+            //.build();
+
+        assert_eq!(builder.operations.len(), 1);
+        if let WriteOperation::UpdateOne { updates, .. } = &builder.operations[0] {
+            assert_eq!(updates.len(), 1);
+            if let UpdateType::Set(doc) = &updates[0] {
+                assert_eq!(doc.get("name").unwrap().as_str().unwrap(), "test1");
+                assert_eq!(doc.get("age").unwrap().as_i32().unwrap(), 20);
+            } else {
+                panic!("Expected Set update type");
+            }
+        } else {
+            panic!("Expected UpdateOne operation");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_different_filters_no_merge() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+        
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "name": "test1" })
+            //.build()
+            .add_update(doc! { "id": 2 })
+            .set(doc! { "name": "test2" });
+            //.build();
+
+        assert_eq!(builder.operations.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mixed_operations() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+        
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "name": "test1" })
+            //.build()
+            //.add_update(doc! { "id": 2 })
+            .delete(doc! { "id": 2 })
+            .add_update(doc! { "id": 3 })
+            .set(doc! { "name": "test3" }); 
+            //.build();
+
+        assert_eq!(builder.operations.len(), 3);
+        
+        match &builder.operations[0] {
+            WriteOperation::UpdateOne { .. } => (),
+            _ => panic!("Expected UpdateOne operation")
+        }
+        
+        match &builder.operations[1] {
+            WriteOperation::DeleteOne { .. } => (),
+            _ => panic!("Expected DeleteOne operation")
+        }
+        
+        match &builder.operations[2] {
+            WriteOperation::UpdateOne { .. } => (),
+            _ => panic!("Expected UpdateOne operation")
+        }
+    }
+
+    #[test]
+    fn test_retryable_error_classification() {
+        let io_error = Error::from(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset"));
+        assert!(BatchUpdateBuilder::<Order>::is_retryable(&io_error));
+        assert!(!BatchUpdateBuilder::<Order>::is_retryable(&Error::custom("duplicate key")));
+    }
+
+    #[test]
+    fn test_bit_merge_composition() {
+        let and_and = UpdateType::Bit(doc! { "flags": { "and": 0b1110 } })
+            .merge(&UpdateType::Bit(doc! { "flags": { "and": 0b0111 } }));
+        match and_and {
+            Some(UpdateType::Bit(merged)) => assert_eq!(merged, doc! { "flags": { "and": 0b0110 } }),
+            _ => panic!("Expected merged Bit update"),
+        }
+
+        let or_or = UpdateType::Bit(doc! { "flags": { "or": 0b0001 }, "mode": { "xor": 1 } })
+            .merge(&UpdateType::Bit(doc! { "flags": { "or": 0b0100_i64 } }));
+        match or_or {
+            Some(UpdateType::Bit(merged)) => assert_eq!(merged, doc! { "flags": { "or": 0b0101_i64 }, "mode": { "xor": 1 } }),
+            _ => panic!("Expected merged Bit update"),
+        }
+
+        let and_xor = UpdateType::Bit(doc! { "flags": { "and": 0b1110 } })
+            .merge(&UpdateType::Bit(doc! { "flags": { "xor": 0b0001 } }));
+        assert!(and_xor.is_none());
+    }
+
+    #[test]
+    fn test_min_max_merge_non_numeric() {
+        let later = DateTime::from_millis(2_000);
+        let earlier = DateTime::from_millis(1_000);
+        match UpdateType::Min(doc! { "created_at": later }).merge(&UpdateType::Min(doc! { "created_at": earlier })) {
+            Some(UpdateType::Min(merged)) => assert_eq!(merged, doc! { "created_at": earlier }),
+            _ => panic!("Expected merged Min update"),
+        }
+        match UpdateType::Max(doc! { "created_at": later }).merge(&UpdateType::Max(doc! { "created_at": earlier })) {
+            Some(UpdateType::Max(merged)) => assert_eq!(merged, doc! { "created_at": later }),
+            _ => panic!("Expected merged Max update"),
+        }
+
+        match UpdateType::Max(doc! { "name": "apple" }).merge(&UpdateType::Max(doc! { "name": "banana" })) {
+            Some(UpdateType::Max(merged)) => assert_eq!(merged, doc! { "name": "banana" }),
+            _ => panic!("Expected merged Max update"),
+        }
+
+        let ts1 = mongodb::bson::Timestamp { time: 10, increment: 2 };
+        let ts2 = mongodb::bson::Timestamp { time: 10, increment: 1 };
+        match UpdateType::Min(doc! { "ts": ts1 }).merge(&UpdateType::Min(doc! { "ts": ts2 })) {
+            Some(UpdateType::Min(merged)) => assert_eq!(merged, doc! { "ts": ts2 }),
+            _ => panic!("Expected merged Min update"),
+        }
+
+        match UpdateType::Max(doc! { "score": 5 }).merge(&UpdateType::Max(doc! { "score": 5.5 })) {
+            Some(UpdateType::Max(merged)) => assert_eq!(merged, doc! { "score": 5.5 }),
+            _ => panic!("Expected merged Max update"),
+        }
+        match UpdateType::Min(doc! { "score": 3_i64 }).merge(&UpdateType::Min(doc! { "score": 2 })) {
+            Some(UpdateType::Min(merged)) => assert_eq!(merged, doc! { "score": 2 }),
+            _ => panic!("Expected merged Min update"),
+        }
+    }
+
+    #[test]
+    fn test_inc_mul_numeric_coercion() {
+        let inc = |a: Bson, b: Bson| match UpdateType::Inc(doc! { "n": a }).merge(&UpdateType::Inc(doc! { "n": b })) {
+            Some(UpdateType::Inc(merged)) => merged.get("n").cloned().unwrap(),
+            _ => panic!("Expected merged Inc update"),
+        };
+        assert_eq!(inc(Bson::Int32(1), Bson::Int32(2)), Bson::Int32(3));
+        assert_eq!(inc(Bson::Int32(1), Bson::Double(1.5)), Bson::Double(2.5));
+        assert_eq!(inc(Bson::Double(1.5), Bson::Int64(2)), Bson::Double(3.5));
+        assert_eq!(inc(Bson::Int32(1), Bson::Int64(2)), Bson::Int64(3));
+        assert_eq!(inc(Bson::Int64(2), Bson::Int32(1)), Bson::Int64(3));
+        assert_eq!(inc(Bson::Int32(i32::MAX), Bson::Int32(1)), Bson::Int64(i32::MAX as i64 + 1));
+
+        let mul = |a: Bson, b: Bson| match UpdateType::Mul(doc! { "n": a }).merge(&UpdateType::Mul(doc! { "n": b })) {
+            Some(UpdateType::Mul(merged)) => merged.get("n").cloned().unwrap(),
+            _ => panic!("Expected merged Mul update"),
+        };
+        assert_eq!(mul(Bson::Int32(2), Bson::Int32(3)), Bson::Int32(6));
+        assert_eq!(mul(Bson::Int32(2), Bson::Double(0.5)), Bson::Double(1.0));
+        assert_eq!(mul(Bson::Int64(2), Bson::Int32(3)), Bson::Int64(6));
+        assert_eq!(mul(Bson::Double(1.5), Bson::Int64(2)), Bson::Double(3.0));
+
+        // Int64 溢出时不合并
+        assert!(UpdateType::Inc(doc! { "n": i64::MAX }).merge(&UpdateType::Inc(doc! { "n": 1_i64 })).is_none());
+        assert!(UpdateType::Mul(doc! { "n": i64::MAX }).merge(&UpdateType::Mul(doc! { "n": 2 })).is_none());
+    }
+
+    #[test]
+    fn test_accumulate_chunk_summaries() {
+        let mut total = SummaryBulkWriteResult::default();
+        let mut chunk = SummaryBulkWriteResult::default();
+        chunk.inserted_count = 2;
+        chunk.matched_count = 3;
+        chunk.modified_count = 3;
+        chunk.upserted_count = 1;
+        chunk.deleted_count = 4;
+
+        BatchUpdateBuilder::<Order>::accumulate_summary(&mut total, &chunk);
+        BatchUpdateBuilder::<Order>::accumulate_summary(&mut total, &chunk);
+
+        assert_eq!(total.inserted_count, 4);
+        assert_eq!(total.matched_count, 6);
+        assert_eq!(total.modified_count, 6);
+        assert_eq!(total.upserted_count, 2);
+        assert_eq!(total.deleted_count, 8);
+    }
+
+    #[test]
+    fn test_pop_merge_keeps_distinct_fields() {
+        let merged = UpdateType::Pop(doc! { "a": 1 })
+            .merge(&UpdateType::Pop(doc! { "b": -1 }))
+            .and_then(|merged| merged.merge(&UpdateType::Pop(doc! { "a": -1 })));
+        match merged {
+            Some(UpdateType::Pop(merged)) => assert_eq!(merged, doc! { "a": -1, "b": -1 }),
+            _ => panic!("Expected merged Pop update"),
+        }
+    }
+
+    #[test]
+    fn test_pull_merge_combines_conditions() {
+        let merged = UpdateType::Pull(doc! { "items": { "status": "expired" } })
+            .merge(&UpdateType::Pull(doc! { "items": { "qty": 0 } }))
+            .and_then(|merged| merged.merge(&UpdateType::Pull(doc! { "items": { "qty": { "$lt": 0 } } })));
+        match merged {
+            Some(UpdateType::Pull(merged)) => assert_eq!(merged, doc! {
+                "items": { "$or": [{ "status": "expired" }, { "qty": 0 }, { "qty": { "$lt": 0 } }] }
+            }),
+            _ => panic!("Expected merged Pull update"),
+        }
+
+        let merged = UpdateType::Pull(doc! { "fruits": { "$in": ["apple"] } })
+            .merge(&UpdateType::Pull(doc! { "fruits": "banana" }));
+        match merged {
+            Some(UpdateType::Pull(merged)) => assert_eq!(merged, doc! { "fruits": { "$in": ["apple", "banana"] } }),
+            _ => panic!("Expected merged Pull update"),
+        }
+    }
+
+    #[test]
+    fn test_pull_operator_predicates_are_not_merged() {
+        let first = UpdateType::Pull(doc! { "scores": { "$gt": 5 } });
+        let second = UpdateType::Pull(doc! { "scores": { "$lt": 0 } });
+        assert!(first.merge(&second).is_none());
+        assert!(first.merge(&UpdateType::Pull(doc! { "scores": { "value": 1 } })).is_none());
+
+        let err = BatchUpdateBuilder::<Order>::build_update_document(&[first, second]).unwrap_err();
+        assert!(err.to_string().contains("Cannot combine $pull updates on field 'scores'"));
+    }
+
+    #[test]
+    fn test_pull_all_merge_dedupes() {
+        let merged = UpdateType::PullAll(doc! { "tags": ["a", "b"] })
+            .merge(&UpdateType::PullAll(doc! { "tags": ["b", "c"] }));
+        match merged {
+            Some(UpdateType::PullAll(merged)) => assert_eq!(merged, doc! { "tags": ["a", "b", "c"] }),
+            _ => panic!("Expected merged PullAll update"),
+        }
+    }
+
+    #[test]
+    fn test_bulk_summary_from_result() {
+        let mut result = SummaryBulkWriteResult::default();
+        result.inserted_count = 1;
+        result.matched_count = 2;
+        result.modified_count = 2;
+        result.upserted_count = 3;
+        result.deleted_count = 4;
+
+        assert_eq!(BulkSummary::from(&result), BulkSummary {
+            inserted: 1,
+            matched: 2,
+            modified: 2,
+            upserted: 3,
+            deleted: 4,
+        });
+        assert_eq!(BulkSummary::from(&SummaryBulkWriteResult::default()), BulkSummary::default());
+    }
+
+    #[test]
+    fn test_add_to_set_each_dedupes_reordered_documents() {
+        let merged = UpdateType::AddToSetEach(doc! { "points": [{ "x": 1, "y": 2 }] })
+            .merge(&UpdateType::AddToSetEach(doc! { "points": [{ "y": 2, "x": 1 }, { "x": 3, "y": 4 }] }));
+        match merged {
+            Some(UpdateType::AddToSetEach(merged)) => assert_eq!(merged, doc! {
+                "points": { "$each": [{ "x": 1, "y": 2 }, { "x": 3, "y": 4 }] }
+            }),
+            _ => panic!("Expected merged AddToSetEach update"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_maybe_update_false_discards_updates() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+
+        builder.maybe_update(false, doc! { "id": 1 })
+            .set(doc! { "name": "skipped" })
+            .maybe_update(true, doc! { "id": 2 })
+            .set(doc! { "name": "kept" })
+            .maybe_delete(false, doc! { "id": 3 })
+            .build();
+
+        assert_eq!(builder.operations.len(), 1);
+        match &builder.operations[0] {
+            WriteOperation::UpdateOne { filter, .. } => assert_eq!(filter, &doc! { "id": 2 }),
+            _ => panic!("Expected UpdateOne operation")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_filter_policy_error() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection)
+            .on_empty_filter(EmptyFilterPolicy::Error);
+
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "name": "test1" })
+            .delete_many(doc! {});
+
+        let err = builder.execute().await.unwrap_err();
+        assert!(err.to_string().contains("Operation 1 (DeleteMany)"));
+    }
+
+    #[tokio::test]
+    async fn test_disable_merge_preserves_order() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection)
+            .disable_merge(true);
+
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "name": "test1" })
+            .inc(doc! { "count": 1 })
+            .set(doc! { "age": 20 })
+            .build();
+
+        if let WriteOperation::UpdateOne { updates, .. } = &builder.operations[0] {
+            assert_eq!(updates.len(), 3);
+            let update_doc = BatchUpdateBuilder::<Order>::build_update_document(updates).unwrap();
+            assert_eq!(update_doc.get_document("$set").unwrap(), &doc! { "name": "test1", "age": 20 });
+            assert_eq!(update_doc.get_document("$inc").unwrap(), &doc! { "count": 1 });
+        } else {
+            panic!("Expected UpdateOne operation");
+        }
+    }
+
+    #[test]
+    fn test_disable_merge_folds_repeated_field() {
+        let updates = vec![
+            UpdateType::Inc(doc! { "n": 1 }),
+            UpdateType::PushEach(doc! { "tags": ["a", "b"] }),
+            UpdateType::Inc(doc! { "n": 2 }),
+            UpdateType::PushEach(doc! { "tags": ["c"] }),
+            UpdateType::Push(doc! { "scores": 1 }),
+            UpdateType::Push(doc! { "scores": 2 }),
+        ];
+        let update_doc = BatchUpdateBuilder::<Order>::build_update_document(&updates).unwrap();
+        assert_eq!(update_doc.get_document("$inc").unwrap(), &doc! { "n": 3 });
+        assert_eq!(update_doc.get_document("$push").unwrap(), &doc! {
+            "tags": { "$each": ["a", "b", "c"] },
+            "scores": { "$each": [1, 2] },
+        });
+    }
+
+    #[test]
+    fn test_repeated_field_that_cannot_fold_errors() {
+        let updates = vec![
+            UpdateType::Bit(doc! { "flags": { "and": 1 } }),
+            UpdateType::Bit(doc! { "flags": { "or": 2 } }),
+        ];
+        let err = BatchUpdateBuilder::<Order>::build_update_document(&updates).unwrap_err();
+        assert!(err.to_string().contains("Cannot combine $bit updates on field 'flags'"));
+    }
+
+    #[tokio::test]
+    async fn test_raw_model_keeps_position() {
+        let collection = get_test_collection().await;
+        let namespace = collection.namespace();
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "name": "test1" })
+            .add_raw_model(DeleteManyModel::builder()
+                .namespace(namespace)
+                .filter(doc! { "status": "expired" })
+                .build()
+                .into())
+            .delete(doc! { "id": 2 })
+            .build();
+
+        assert_eq!(builder.operations.len(), 2);
+        assert_eq!(builder.raw_models.len(), 1);
+        assert_eq!(builder.raw_models[0].0, 1);
+    }
+
+    #[tokio::test]
+    async fn test_extend_merges_same_filter() {
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(get_test_collection().await);
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "name": "test1" })
+            .delete(doc! { "id": 2 });
+
+        let mut other: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(get_test_collection().await);
+        other.add_update(doc! { "id": 1 })
+            .set(doc! { "status": "paid" })
+            .add_update(doc! { "id": 3 })
+            .inc(doc! { "count": 1 });
+
+        builder.extend(other).build();
+
+        assert_eq!(builder.operations.len(), 3);
+        if let WriteOperation::UpdateOne { filter, updates, .. } = &builder.operations[0] {
+            assert_eq!(filter, &doc! { "id": 1 });
+            match &updates[0] {
+                UpdateType::Set(set_doc) => assert_eq!(set_doc, &doc! { "name": "test1", "status": "paid" }),
+                _ => panic!("Expected Set update"),
+            }
+        } else {
+            panic!("Expected UpdateOne operation");
+        }
+        assert!(matches!(builder.operations[1], WriteOperation::DeleteOne { .. }));
+        assert!(matches!(builder.operations[2], WriteOperation::UpdateOne { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_reordered_filters_merge() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+
+        builder.add_update(doc! { "a": 1, "b": { "x": 1, "y": [1, { "m": 1, "n": 2 }] } })
+            .set(doc! { "name": "test1" })
+            .add_update(doc! { "b": { "y": [1, { "n": 2, "m": 1 }], "x": 1 }, "a": 1 })
+            .inc(doc! { "count": 1 })
+            .add_update(doc! { "a": 1, "b": { "x": 1, "y": [{ "m": 1, "n": 2 }, 1] } })
+            .inc(doc! { "count": 1 })
+            .build();
+
+        assert_eq!(builder.operations.len(), 2);
+        if let WriteOperation::UpdateOne { updates, .. } = &builder.operations[0] {
+            assert_eq!(updates.len(), 2);
+        } else {
+            panic!("Expected UpdateOne operation");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_updates_merges() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "name": "test1" })
+            .apply_updates(vec![
+                UpdateType::Set(doc! { "status": "paid" }),
+                UpdateType::Inc(doc! { "version": 1 }),
+            ])
+            .build();
+
+        if let WriteOperation::UpdateOne { updates, .. } = &builder.operations[0] {
+            assert_eq!(updates.len(), 2);
+            match &updates[0] {
+                UpdateType::Set(set_doc) => assert_eq!(set_doc, &doc! { "name": "test1", "status": "paid" }),
+                _ => panic!("Expected Set update"),
+            }
+        } else {
+            panic!("Expected UpdateOne operation");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_model_sources_skip_empty_filter() {
+        let collection = get_test_collection().await;
+        let namespace = collection.namespace();
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+
+        builder.delete(doc! {})
+            .add_update(doc! { "id": 1 })
+            .set(doc! { "name": "test1" })
+            .add_raw_model(DeleteManyModel::builder()
+                .namespace(namespace)
+                .filter(doc! { "status": "expired" })
+                .build()
+                .into())
+            .delete(doc! { "id": 2 });
+
+        let models = builder.build_models().unwrap();
+        assert_eq!(models.len(), 3);
+        assert_eq!(builder.model_sources, vec![Some(1), None, Some(2)]);
+    }
+
+    #[tokio::test]
+    async fn test_merge_keeps_original_position() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "name": "test1" })
+            .delete(doc! { "id": 2 })
+            .add_update(doc! { "id": 1 })
+            .inc(doc! { "count": 1 })
+            .build();
+
+        assert_eq!(builder.operations.len(), 2);
+        match &builder.operations[0] {
+            WriteOperation::UpdateOne { filter, updates, .. } => {
+                assert_eq!(filter, &doc! { "id": 1 });
+                assert_eq!(updates.len(), 2);
+            }
+            _ => panic!("Expected UpdateOne operation")
+        }
+        match &builder.operations[1] {
+            WriteOperation::DeleteOne { .. } => (),
+            _ => panic!("Expected DeleteOne operation")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_to_extended_json_round_trips_object_id() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+        let id = mongodb::bson::oid::ObjectId::new();
+
+        builder.add_update(doc! { "_id": id })
+            .set(doc! { "name": "test1" });
+
+        let json = builder.to_extended_json().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let restored = Bson::try_from(value).unwrap();
+        let first = restored.as_array().unwrap()[0].as_document().unwrap();
+        assert_eq!(first.get_document("filter").unwrap().get_object_id("_id").unwrap(), id);
+        assert_eq!(first.get_str("op").unwrap(), "updateOne");
+    }
+
+    #[tokio::test]
+    async fn test_upsert_many_by_key() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Document> = BatchUpdateBuilder::new(collection.clone_with_type());
+
+        builder.upsert_many("sku", vec![
+            doc! { "sku": "a", "price": 1 },
+            doc! { "sku": "b", "price": 2 },
+        ]).unwrap();
+
+        assert_eq!(builder.operations.len(), 2);
+        match &builder.operations[1] {
+            WriteOperation::UpdateOne { filter, upsert, .. } => {
+                assert_eq!(filter, &doc! { "sku": "b" });
+                assert!(*upsert);
+            }
+            _ => panic!("Expected UpdateOne operation")
+        }
+
+        assert!(builder.upsert_many("sku", vec![doc! { "price": 3 }]).is_err());
+        assert_eq!(builder.operations.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unordered_continues_after_failure() {
+        let collection: Collection<Document> = get_test_collection().await.clone_with_type();
+        let id = mongodb::bson::oid::ObjectId::new();
+        collection.insert_one(doc! { "_id": id, "name": "existing" }).await.unwrap();
+
+        let mut builder: BatchUpdateBuilder<Document> = BatchUpdateBuilder::new(collection.clone()).ordered(false);
+        builder.insert(doc! { "_id": id, "name": "duplicate" })
+            .add_update(doc! { "_id": id })
+            .set(doc! { "name": "updated" });
+
+        assert!(builder.execute().await.is_err());
+        let stored = collection.find_one(doc! { "_id": id }).await.unwrap().unwrap();
+        assert_eq!(stored.get_str("name").unwrap(), "updated");
+        collection.delete_one(doc! { "_id": id }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replace_one_model() {
+        let collection = get_test_collection().await;
+        let namespace = collection.namespace();
+        let mut builder: BatchUpdateBuilder<Document> = BatchUpdateBuilder::new(collection.clone_with_type());
+
+        builder.replace(doc! { "id": 1 }, doc! { "id": 1, "name": "replaced" })
+            .replace(doc! {}, doc! { "id": 2 });
+
+        let models = builder.build_models().unwrap();
+        assert_eq!(models.len(), 1);
+        match &models[0] {
+            WriteModel::ReplaceOne(model) => {
+                assert_eq!(model.namespace, namespace);
+                assert_eq!(model.filter, doc! { "id": 1 });
+                assert_eq!(model.replacement, doc! { "id": 1, "name": "replaced" });
+            }
+            _ => panic!("Expected ReplaceOne model")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_many_model() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+
+        builder.add_update_many(doc! { "status": "pending" })
+            .set(doc! { "status": "expired" })
+            .add_update(doc! { "status": "pending" })
+            .set(doc! { "flag": true })
+            .add_update_many(doc! { "status": "pending" })
+            .inc(doc! { "version": 1 })
+            .add_update_many(doc! {})
+            .set(doc! { "status": "all" });
+
+        let models = builder.build_models().unwrap();
+        assert_eq!(models.len(), 2);
+        match &models[0] {
+            WriteModel::UpdateMany(model) => {
+                assert_eq!(model.filter, doc! { "status": "pending" });
+                match &model.update {
+                    UpdateModifications::Document(update) => assert_eq!(update, &doc! {
+                        "$set": { "status": "expired" },
+                        "$inc": { "version": 1 },
+                    }),
+                    _ => panic!("Expected update document"),
+                }
+            }
+            _ => panic!("Expected UpdateMany model")
+        }
+        assert!(matches!(models[1], WriteModel::UpdateOne(_)));
+    }
+
+    #[tokio::test]
+    async fn test_array_filters_on_model() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "arr.$[elem].x": 1 })
+            .array_filters(vec![doc! { "elem.x": { "$lt": 1 } }]);
+
+        let models = builder.build_models().unwrap();
+        match &models[0] {
+            WriteModel::UpdateOne(model) => {
+                match &model.update {
+                    UpdateModifications::Document(update) => assert_eq!(update, &doc! { "$set": { "arr.$[elem].x": 1 } }),
+                    _ => panic!("Expected update document"),
+                }
+                assert_eq!(model.array_filters, Some(vec![doc! { "elem.x": { "$lt": 1 } }]));
+            }
+            _ => panic!("Expected UpdateOne model")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collation_on_models() {
+        use mongodb::options::CollationStrength;
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+        let collation = Collation::builder().locale("en").strength(CollationStrength::Secondary).build();
+
+        builder.collation(collation.clone())
+            .add_update(doc! { "name": "alice" })
+            .set(doc! { "vip": true })
+            .collation(collation.clone())
+            .delete(doc! { "name": "BOB" })
+            .collation(collation.clone());
+
+        let models = builder.build_models().unwrap();
+        match &models[0] {
+            WriteModel::UpdateOne(model) => assert_eq!(model.collation.as_ref().map(|c| c.locale.as_str()), Some("en")),
+            _ => panic!("Expected UpdateOne model")
+        }
+        match &models[1] {
+            WriteModel::DeleteOne(model) => assert_eq!(model.collation.as_ref().map(|c| c.locale.as_str()), Some("en")),
+            _ => panic!("Expected DeleteOne model")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collation_not_applied_to_earlier_delete() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+        let collation = Collation::builder().locale("en").build();
+
+        builder.delete(doc! { "name": "alice" })
+            .maybe_delete(false, doc! { "name": "bob" })
+            .collation(collation.clone())
+            .delete_many(doc! { "name": "carol" })
+            .build()
+            .hint(Bson::from("name_1"))
+            .collation(collation);
+
+        assert!(builder.operations.iter().all(|operation| match operation {
+            WriteOperation::DeleteOne { options, .. } | WriteOperation::DeleteMany { options, .. } =>
+                options.collation.is_none() && options.hint.is_none(),
+            _ => false,
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_hint_on_update_model() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
 
-    pub fn upsert(&mut self, upsert: bool) -> &mut Self {
-        if let Some(context) = &mut self.current_context {
-            context.is_upsert = upsert;
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "name": "test1" })
+            .hint(Bson::Document(doc! { "id": 1 }))
+            .add_update(doc! { "id": 2 })
+            .set(doc! { "name": "test2" })
+            .hint(Bson::from("id_1"));
+
+        let models = builder.build_models().unwrap();
+        match (&models[0], &models[1]) {
+            (WriteModel::UpdateOne(first), WriteModel::UpdateOne(second)) => {
+                assert!(matches!(&first.hint, Some(Hint::Keys(keys)) if keys == &doc! { "id": 1 }));
+                assert!(matches!(&second.hint, Some(Hint::Name(name)) if name == "id_1"));
+            }
+            _ => panic!("Expected UpdateOne models")
         }
-        self
     }
 
-    pub fn delete(&mut self, filter: Document) -> &mut Self {
-        self.commit_context();
-        self.operations.push(WriteOperation::DeleteOne { filter });
-        self
-    }
+    #[tokio::test]
+    async fn test_insert_serialization_error_is_returned() {
+        let collection = get_test_collection().await;
+        // i32 不能序列化为文档
+        let mut builder: BatchUpdateBuilder<i32> = BatchUpdateBuilder::new(collection.clone_with_type());
+        builder.insert(42);
 
-    pub fn delete_many(&mut self, filter: Document) -> &mut Self {
-        self.commit_context();
-        self.operations.push(WriteOperation::DeleteMany { filter });
-        self
+        assert!(builder.build_models().is_err());
+        assert!(builder.execute().await.is_err());
     }
 
-    pub fn insert(&mut self, document: T) -> &mut Self {
-        self.commit_context();
-        self.operations.push(WriteOperation::InsertOne { document });
-        self
+    #[tokio::test]
+    async fn test_len_counts_uncommitted_context() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
+        assert!(builder.is_empty());
+
+        builder.add_update(doc! { "id": 1 });
+        assert_eq!(builder.len(), 0);
+        builder.set(doc! { "name": "test1" });
+        assert_eq!(builder.len(), 1);
+        builder.delete(doc! { "id": 2 })
+            .add_update(doc! { "id": 1 })
+            .inc(doc! { "count": 1 });
+        assert_eq!(builder.len(), 2);
     }
 
-    pub fn build(&mut self) -> &mut Self {
-        self.commit_context();
-        self
+    #[tokio::test]
+    async fn test_clear_then_reuse() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection).ordered(false);
+
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "name": "test1" })
+            .delete(doc! { "id": 2 })
+            .add_update(doc! { "id": 3 })
+            .set(doc! { "name": "test3" });
+        builder.clear();
+        assert!(builder.is_empty());
+        assert!(!builder.ordered);
+
+        builder.add_update(doc! { "id": 4 })
+            .set(doc! { "name": "test4" });
+        let models = builder.build_models().unwrap();
+        assert_eq!(models.len(), 1);
     }
- 
-        /*
-        filter_map 的工作方式是：
-        对每个元素应用转换函数
-        如果函数返回 Some(x)，则保留值 x
-        如果函数返回 None，则跳过该元素
-        所以：
-        空的过滤条件（filter.is_empty()）会返回 None
-        没有更新操作的更新（updates.is_empty()）会返回 None
-        这些 None 值会被 filter_map 自动过滤掉
-        最终的 write_models 只包含有效的操作
-        这是一个安全的实现，可以防止意外的全表更新或删除操作。
-     */
-    pub async fn execute(&mut self) -> Result<SummaryBulkWriteResult, Error> {
-        self.build();
 
-        println!("mongodb_bulk_writer_builder>>>>begin to execute...");
-        // 原有的 execute 实现保持不变
-        let write_models: Vec<WriteModel> = self.operations.iter()
-            .filter_map(|operation| match operation {
-                WriteOperation::UpdateOne { filter, updates, upsert } => {
-                    // 如果 filter为{} 则不进行更新，因为这是一个破坏性极大的操作，会把整个表的数据都更新，这种操作要禁止
-                    if !filter.is_empty() && !updates.is_empty() {
-                        let mut update_doc = Document::new();
-                        for update in updates {
-                            let doc = update.to_document();
-                            for (k, v) in doc.iter() {
-                                update_doc.insert(k, v.clone());
-                            }
-                        }
-                        
-                        Some(UpdateOneModel::builder()
-                            .namespace(self.collection.namespace())
-                            .filter(filter.clone())
-                            .update(UpdateModifications::Document(update_doc))
-                            .upsert(Some(*upsert))
-                            .build()
-                            .into())
-                    } else {
-                        None
-                    }
-                },
-                WriteOperation::DeleteOne { filter } => {
-                    if !filter.is_empty() {
-                        Some(DeleteOneModel::builder()
-                            .namespace(self.collection.namespace())
-                            .filter(filter.clone())
-                            .build()
-                            .into())
-                    } else {
-                        None
-                    }
-                },
-                WriteOperation::DeleteMany { filter } => {
-                    if !filter.is_empty() {
-                        Some(DeleteManyModel::builder()
-                            .namespace(self.collection.namespace())
-                            .filter(filter.clone())
-                            .build()
-                            .into())
-            } else {
-                None
-                    }
-                },
-                WriteOperation::InsertOne { document } => {
-                    Some(InsertOneModel::builder()
-                        .namespace(self.collection.namespace())
-                        .document(to_document(document).unwrap())
-                        .build()
-                        .into())
-                },
-            })
-            .collect();
-        // Print all database operations before executing
-        println!("Executing database operations:");
-        for (i, op) in self.operations.iter().enumerate() {
-            match op {
-                WriteOperation::UpdateOne { filter, updates, upsert } => {
-                    println!("Operation {}: UpdateOne", i);
-                    println!("  Filter: {:?}", filter);
-                    println!("  Updates: {:?}", updates);
-                    println!("  Upsert: {:?}", upsert);
-                },
-                WriteOperation::DeleteOne { filter } => {
-                    println!("Operation {}: DeleteOne", i);
-                    println!("  Filter: {:?}", filter);
-                },
-                WriteOperation::DeleteMany { filter } => {
-                    println!("Operation {}: DeleteMany", i);
-                    println!("  Filter: {:?}", filter);
-                },
-                WriteOperation::InsertOne { document } => {
-                    println!("Operation {}: InsertOne", i);
-                    //println!("  Document: {:?}", document);
+    #[tokio::test]
+    async fn test_insert_many_models() {
+        let collection = get_test_collection().await;
+        let namespace = collection.namespace();
+        let mut builder: BatchUpdateBuilder<Document> = BatchUpdateBuilder::new(collection.clone_with_type());
+
+        builder.insert_many(Vec::new())
+            .insert_many(vec![doc! { "id": 1 }, doc! { "id": 2 }, doc! { "id": 3 }]);
+
+        let models = builder.build_models().unwrap();
+        assert_eq!(models.len(), 3);
+        for (index, model) in models.iter().enumerate() {
+            match model {
+                WriteModel::InsertOne(model) => {
+                    assert_eq!(model.namespace, namespace);
+                    assert_eq!(model.document.get_i32("id").unwrap(), index as i32 + 1);
                 }
+                _ => panic!("Expected InsertOne model")
             }
         }
-        println!("write_models>>>>{:?}",write_models);
-        if write_models.is_empty(){ 
-            return Ok(SummaryBulkWriteResult::default());
-        }
-        let result = self.collection.client().bulk_write(write_models).await;
-        println!("result>>>>{:?}",result);
-        result
     }
-  
-}
 
-impl BatchUpdateContext{
-    fn add_update_type(&mut self, new_update: UpdateType) {
-        // 尝试合并相同类型的操作
-        let mut merged = false;
-        for existing_update in &mut self.updates {
-            if let Some(merged_update) = existing_update.merge(&new_update) {
-                *existing_update = merged_update;
-                merged = true;
-                break;
-            }
-        }
-        
-        if !merged {
-            self.updates.push(new_update);
-        }
+    // 只需要能通过编译：确认会话版本的签名可以在事务代码中使用
+    #[allow(dead_code)]
+    async fn execute_with_session_type_checks(builder: &mut BatchUpdateBuilder<Order>, session: &mut ClientSession) -> Result<i64, Error> {
+        let summary = builder.execute_with_session(session).await?;
+        Ok(summary.modified_count)
     }
-}
 
+    #[tokio::test]
+    async fn test_write_concern_applied_to_options() {
+        let collection = get_test_collection().await;
+        let builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection.clone());
+        assert!(builder.bulk_write_options().write_concern.is_none());
+
+        let builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection)
+            .ordered(false)
+            .write_concern(WriteConcern::majority());
+        let options = builder.bulk_write_options();
+        assert_eq!(options.ordered, Some(false));
+        assert_eq!(options.write_concern, Some(WriteConcern::majority()));
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use mongodb::{Client, bson::doc};
-    use crate::models::order::Order;
+    #[tokio::test]
+    async fn test_bypass_document_validation_option() {
+        let collection = get_test_collection().await;
+        let builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection.clone());
+        assert_eq!(builder.bulk_write_options().bypass_document_validation, None);
 
-    async fn get_test_collection() -> Collection<Order> {
-        let client = Client::with_uri_str("mongodb://localhost:27017").await.unwrap();
-        client.database("test").collection("test_collection")
+        let builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection).bypass_document_validation(true);
+        assert_eq!(builder.bulk_write_options().bypass_document_validation, Some(true));
     }
 
     #[tokio::test]
-    async fn test_different_update_types_same_filter() {
+    async fn test_with_timestamps_stamps_updates_and_upserts() {
         let collection = get_test_collection().await;
-        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
-        let filter = doc! { "id": 1 };
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection)
+            .with_timestamps("updated_at", Some("created_at"));
 
-        builder.add_update(filter.clone())
-                        .set(doc! { "name": "test1" })
-                        .inc(doc! { "count": 1 });
-                        //.build();
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "name": "test1" })
+            .upsert_key("id", 2)
+            .set(doc! { "name": "test2" })
+            .add_update(doc! { "id": 3 })
+            .current_date(doc! { "updated_at": { "$type": "timestamp" } });
 
-        assert_eq!(builder.operations.len(), 1);
-        if let WriteOperation::UpdateOne { updates, .. } = &builder.operations[0] {
-            assert_eq!(updates.len(), 2);
-            match (&updates[0], &updates[1]) {
-                (UpdateType::Set(set_doc), UpdateType::Inc(inc_doc)) => {
-                    assert_eq!(set_doc.get("name").unwrap().as_str().unwrap(), "test1");
-                    assert_eq!(inc_doc.get("count").unwrap().as_i32().unwrap(), 1);
-                }
-                _ => panic!("Unexpected update types")
-            }
-        } else {
-            panic!("Expected UpdateOne operation");
-        }
+        let models = builder.build_models().unwrap();
+        let updates: Vec<Document> = models.iter().map(|model| match model {
+            WriteModel::UpdateOne(model) => match &model.update {
+                UpdateModifications::Document(update) => update.clone(),
+                _ => panic!("Expected update document"),
+            },
+            _ => panic!("Expected UpdateOne model"),
+        }).collect();
+
+        assert_eq!(updates[0].get_document("$currentDate").unwrap(), &doc! { "updated_at": true });
+        assert!(!updates[0].contains_key("$setOnInsert"));
+
+        assert_eq!(updates[1].get_document("$currentDate").unwrap(), &doc! { "updated_at": true });
+        let set_on_insert = updates[1].get_document("$setOnInsert").unwrap();
+        assert!(set_on_insert.contains_key("created_at"));
+        assert_eq!(set_on_insert.get_i32("id").unwrap(), 2);
+
+        assert_eq!(updates[2].get_document("$currentDate").unwrap(), &doc! { "updated_at": { "$type": "timestamp" } });
     }
 
     #[tokio::test]
-    async fn test_merge_same_update_type_and_filter() {
+    async fn test_conflicting_operator_paths() {
         let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection.clone());
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "count": 1 })
+            .inc(doc! { "count": 1 });
+        assert!(builder.build_models().is_err());
+
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection.clone());
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "a.b": 1 })
+            .inc(doc! { "a": 1 });
+        assert!(builder.build_models().is_err());
+
         let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
-        let filter = doc! { "id": 1 };
+        builder.add_update(doc! { "id": 1 })
+            .set(doc! { "a.b": 1, "ab": 2 })
+            .inc(doc! { "a.c": 1 });
+        assert!(builder.build_models().is_ok());
+    }
 
-        builder.add_update(filter.clone())
-            .set(doc! { "name": "test1" })
-            .set(doc! { "age": 20 });
-            //.build();
+    #[tokio::test]
+    async fn test_add_update_with_scopes() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
 
-        assert_eq!(builder.operations.len(), 1);
-        if let WriteOperation::UpdateOne { updates, .. } = &builder.operations[0] {
-            assert_eq!(updates.len(), 1);
-            if let UpdateType::Set(doc) = &updates[0] {
-                assert_eq!(doc.get("name").unwrap().as_str().unwrap(), "test1");
-                assert_eq!(doc.get("age").unwrap().as_i32().unwrap(), 20);
-            } else {
-                panic!("Expected Set update type");
+        builder.add_update_with(doc! { "id": 1 }, |b| {
+            b.set(doc! { "name": "test1" });
+            b.add_update_with(doc! { "id": 3 }, |b| {
+                b.set(doc! { "name": "nested" });
+            });
+        })
+        .add_update_with(doc! { "id": 2 }, |b| {
+            b.inc(doc! { "count": 1 });
+        });
+
+        assert!(builder.current_context.is_none());
+        assert_eq!(builder.operations.len(), 2);
+        match (&builder.operations[0], &builder.operations[1]) {
+            (WriteOperation::UpdateOne { filter: first, updates, .. }, WriteOperation::UpdateOne { filter: second, .. }) => {
+                assert_eq!(first, &doc! { "id": 1 });
+                assert_eq!(updates.len(), 1);
+                assert_eq!(second, &doc! { "id": 2 });
             }
-        } else {
-            panic!("Expected UpdateOne operation");
+            _ => panic!("Expected two UpdateOne operations")
         }
+        let err = builder.build_models().unwrap_err();
+        assert!(err.to_string().contains("add_update_with cannot be nested"));
     }
 
     #[tokio::test]
-    async fn test_different_filters_no_merge() {
+    async fn test_auto_timestamp_keeps_explicit_value() {
+        let collection = get_test_collection().await;
+        let builder: BatchUpdateBuilder<Document> = BatchUpdateBuilder::new(collection.clone_with_type())
+            .auto_timestamp("created_at");
+        let explicit = DateTime::from_millis(0);
+
+        let stamped = builder.build_write_model(&WriteOperation::InsertOne { document: doc! { "id": 1 } }).unwrap();
+        let kept = builder.build_write_model(&WriteOperation::InsertOne {
+            document: doc! { "id": 2, "created_at": explicit },
+        }).unwrap();
+
+        match (stamped, kept) {
+            (Some(WriteModel::InsertOne(stamped)), Some(WriteModel::InsertOne(kept))) => {
+                assert!(stamped.document.get_datetime("created_at").is_ok());
+                assert_eq!(kept.document.get_datetime("created_at").unwrap(), &explicit);
+            }
+            _ => panic!("Expected InsertOne models")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_and_push_each_conflict_on_same_field() {
         let collection = get_test_collection().await;
         let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
-        
+
         builder.add_update(doc! { "id": 1 })
-            .set(doc! { "name": "test1" })
-            //.build()
-            .add_update(doc! { "id": 2 })
-            .set(doc! { "name": "test2" });
-            //.build();
+            .push(doc! { "tags": "a" })
+            .push_each(doc! { "tags": ["b", "c"] })
+            .build();
 
-        assert_eq!(builder.operations.len(), 2);
+        if let WriteOperation::UpdateOne { updates, .. } = &builder.operations[0] {
+            let err = BatchUpdateBuilder::<Order>::build_update_document(updates).unwrap_err();
+            assert!(err.to_string().contains("$push"));
+        } else {
+            panic!("Expected UpdateOne operation");
+        }
+
+        let distinct_fields = vec![
+            UpdateType::Push(doc! { "tags": "a" }),
+            UpdateType::PushEach(doc! { "scores": [1, 2] }),
+        ];
+        let update_doc = BatchUpdateBuilder::<Order>::build_update_document(&distinct_fields).unwrap();
+        assert_eq!(update_doc.get_document("$push").unwrap().len(), 2);
     }
 
     #[tokio::test]
-    async fn test_mixed_operations() {
+    async fn test_base_filter_scopes_operations() {
         let collection = get_test_collection().await;
-        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection);
-        
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection)
+            .with_base_filter(doc! { "tenant": "t1" });
+
         builder.add_update(doc! { "id": 1 })
             .set(doc! { "name": "test1" })
-            //.build()
-            //.add_update(doc! { "id": 2 })
-            .delete(doc! { "id": 2 })
-            .add_update(doc! { "id": 3 })
-            .set(doc! { "name": "test3" }); 
-            //.build();
+            .delete(doc! { "tenant": "t2", "id": 2 })
+            .build();
 
-        assert_eq!(builder.operations.len(), 3);
-        
         match &builder.operations[0] {
-            WriteOperation::UpdateOne { .. } => (),
+            WriteOperation::UpdateOne { filter, .. } => assert_eq!(filter, &doc! { "tenant": "t1", "id": 1 }),
             _ => panic!("Expected UpdateOne operation")
         }
-        
         match &builder.operations[1] {
-            WriteOperation::DeleteOne { .. } => (),
+            WriteOperation::DeleteOne { filter, .. } => assert_eq!(filter, &doc! {
+                "$and": [{ "tenant": "t1" }, { "tenant": "t2", "id": 2 }]
+            }),
             _ => panic!("Expected DeleteOne operation")
         }
-        
-        match &builder.operations[2] {
-            WriteOperation::UpdateOne { .. } => (),
-            _ => panic!("Expected UpdateOne operation")
+    }
+
+    #[tokio::test]
+    async fn test_filter_struct_for_any_operation() {
+        #[derive(Serialize)]
+        struct OrderQuery { status: Option<String>, user_id: Option<i64> }
+
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection)
+            .with_base_filter(doc! { "tenant": "t1" });
+
+        let filter = builder.filter_struct(&OrderQuery { status: Some("expired".to_string()), user_id: None }).unwrap();
+        assert_eq!(filter, doc! { "status": "expired" });
+
+        builder.delete_many(filter);
+        match &builder.operations[0] {
+            WriteOperation::DeleteMany { filter, .. } => assert_eq!(filter, &doc! { "tenant": "t1", "status": "expired" }),
+            _ => panic!("Expected DeleteMany operation")
+        }
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Created and updated timestamp fields must differ")]
+    async fn test_with_timestamps_rejects_same_field() {
+        let collection = get_test_collection().await;
+        let _builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection)
+            .with_timestamps("ts", Some("ts"));
+    }
+
+    #[tokio::test]
+    async fn test_updated_stamp_skips_rename_target() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection)
+            .with_timestamps("updated_at", None);
+
+        builder.add_update(doc! { "id": 1 })
+            .rename(doc! { "modified": "updated_at" });
+
+        let models = builder.build_models().unwrap();
+        match &models[0] {
+            WriteModel::UpdateOne(model) => match &model.update {
+                UpdateModifications::Document(update) => {
+                    assert!(!update.contains_key("$currentDate"));
+                    assert_eq!(update.get_document("$rename").unwrap(), &doc! { "modified": "updated_at" });
+                }
+                _ => panic!("Expected update document"),
+            },
+            _ => panic!("Expected UpdateOne model")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_error_carries_failed_operations() {
+        let collection: Collection<Document> = get_test_collection().await.clone_with_type();
+        collection.delete_many(doc! { "_id": "run_failure" }).await.unwrap();
+        let mut builder: BatchUpdateBuilder<Document> = BatchUpdateBuilder::new(collection);
+
+        builder.insert(doc! { "_id": "run_failure" })
+            .insert(doc! { "_id": "run_failure" });
+
+        let err = builder.run().await.unwrap_err();
+        assert_eq!(err.failed.len(), 1);
+        assert_eq!(err.failed[0].operation_index, Some(1));
+        match &err.failed[0].operation {
+            Some(WriteOperation::InsertOne { document }) => assert_eq!(document, &doc! { "_id": "run_failure" }),
+            _ => panic!("Expected InsertOne operation")
         }
     }
+
+    #[tokio::test]
+    async fn test_execute_chunked_rejects_idempotency_key() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection)
+            .idempotency_key("job:1");
+        builder.add_update(doc! { "id": 1 }).set(doc! { "name": "test1" });
+
+        let err = builder.execute_chunked(10).await.unwrap_err();
+        assert_eq!(err.errors.len(), 1);
+        assert_eq!(err.summary.inserted_count, 0);
+        assert!(err.to_string().contains("idempotency_key"));
+        assert_eq!(builder.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_base_filter_keeps_empty_filter_empty() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection.clone())
+            .with_base_filter(doc! { "tenant": "t1" });
+        builder.delete_many(doc! {});
+        assert!(builder.build_models().unwrap().is_empty());
+
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection)
+            .with_base_filter(doc! { "tenant": "t1" })
+            .on_empty_filter(EmptyFilterPolicy::Error);
+        builder.delete_many(doc! {});
+        let err = builder.build_models().unwrap_err();
+        assert!(err.to_string().contains("Operation 0 (DeleteMany) has an empty filter"));
+    }
+
+    #[tokio::test]
+    async fn test_safe_delete_requires_selective_filter_or_confirmation() {
+        let collection = get_test_collection().await;
+        let mut builder: BatchUpdateBuilder<Order> = BatchUpdateBuilder::new(collection)
+            .safe_delete(true);
+
+        builder.delete_many(doc! { "user_id": 1 })
+            .delete_many(doc! { "expired": { "$exists": true } });
+        let err = builder.execute().await.unwrap_err();
+        assert!(err.to_string().contains("Operation 1 (DeleteMany)"));
+
+        builder.confirm_delete_many(true);
+        assert_eq!(builder.find_unsafe_delete_many(), None);
+    }
+
+    #[test]
+    fn test_selective_filter_rules() {
+        type Builder = BatchUpdateBuilder<Order>;
+        assert!(Builder::is_selective_filter(&doc! { "$and": [{ "age": { "$gt": 1 } }, { "user_id": 1 }] }));
+        assert!(Builder::is_selective_filter(&doc! { "name": { "$regex": "^abc" } }));
+        assert!(!Builder::is_selective_filter(&doc! { "$and": [{ "age": { "$gt": 1 } }] }));
+        assert!(!Builder::is_selective_filter(&doc! { "name": { "$regex": ".*" } }));
+        assert!(!Builder::is_selective_filter(&doc! {
+            "name": Bson::RegularExpression(mongodb::bson::Regex { pattern: "^".to_string(), options: String::new() })
+        }));
+        assert!(!Builder::is_selective_filter(&doc! { "expired": { "$exists": true } }));
+        assert!(!Builder::is_selective_filter(&doc! { "status": { "$ne": "paid" } }));
+        assert!(!Builder::is_selective_filter(&doc! { "status": { "$in": ["paid", null] } }));
+    }
 }
@@ -0,0 +1,50 @@
+//本代码有由AI生成
+use mongodb::{
+    bson::{Document, Bson},
+    error::Result,
+};
+use serde::Serialize;
+
+// 查询结构体转 filter：None（Null）字段被跳过，嵌套结构体展开为点路径 { "address.city": "x" }，
+// 这样只匹配给出的子字段而不是要求整个子文档完全相等；数组保持原样
+// lazy_mongo_aggregates 的 filter_struct 与 mongo_bulk_writer_builder 的 filter_struct 共用
+pub(crate) fn struct_to_filter<Q: Serialize>(query: &Q) -> Result<Document> {
+    fn flatten(prefix: &str, doc: Document, out: &mut Document) {
+        for (k, v) in doc {
+            let path = if prefix.is_empty() { k } else { format!("{}.{}", prefix, k) };
+            match v {
+                Bson::Null => {}
+                Bson::Document(nested) if !nested.keys().any(|key| key.starts_with('$')) => {
+                    flatten(&path, nested, out);
+                }
+                other => {
+                    out.insert(path, other);
+                }
+            }
+        }
+    }
+    let mut filter = Document::new();
+    flatten("", mongodb::bson::to_document(query)?, &mut filter);
+    Ok(filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    #[test]
+    fn test_struct_to_filter_skips_none_and_flattens() {
+        #[derive(Serialize)]
+        struct Address { city: String }
+        #[derive(Serialize)]
+        struct Query { status: Option<String>, user_id: Option<i64>, address: Address }
+
+        let filter = struct_to_filter(&Query {
+            status: Some("paid".to_string()),
+            user_id: None,
+            address: Address { city: "Shenzhen".to_string() },
+        }).unwrap();
+        assert_eq!(filter, doc! { "status": "paid", "address.city": "Shenzhen" });
+    }
+}
@@ -43,11 +43,81 @@
 
 
 use crate::models::redis::MyError;
-use r2d2_redis::redis::{pipe, Pipeline, Value, FromRedisValue};
+use r2d2_redis::redis::{cmd, pipe, Connection, Pipeline, RedisResult, Value, FromRedisValue};
 use r2d2::PooledConnection;
 use r2d2_redis::RedisConnectionManager;
 use std::collections::HashMap;
 
+// get_typed 的返回值，按 TYPE 结果区分
+#[derive(Debug, Clone, PartialEq)]
+pub enum RedisValue {
+    None,
+    String(String),
+    List(Vec<String>),
+    Set(Vec<String>),
+    ZSet(Vec<(String, f64)>),
+    Hash(HashMap<String, String>),
+}
+
+// List 的弹出/移动方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Side::Left => "LEFT",
+            Side::Right => "RIGHT",
+        }
+    }
+}
+
+// Sorted Set 的弹出方向：最小分数或最大分数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MinMax {
+    Min,
+    Max,
+}
+
+impl MinMax {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MinMax::Min => "MIN",
+            MinMax::Max => "MAX",
+        }
+    }
+}
+
+// 统一 ZRANGE（Redis 6.2+）的选项
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ZRangeBy {
+    #[default]
+    Index,
+    ByScore,
+    ByLex,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ZRangeOpts {
+    pub by: ZRangeBy,
+    pub rev: bool,
+    pub limit: Option<(isize, isize)>,  // (offset, count)，仅 BYSCORE/BYLEX 可用
+    pub with_scores: bool,
+}
+
+// SORT 的选项，get 中每个模式对应结果中的一列，"#" 表示元素本身
+#[derive(Debug, Clone, Default)]
+pub struct SortOpts {
+    pub by: Option<String>,
+    pub get: Vec<String>,
+    pub limit: Option<(isize, isize)>,  // (offset, count)
+    pub alpha: bool,
+    pub desc: bool,
+}
+
 pub struct RedisPipeline {
     conn: PooledConnection<RedisConnectionManager>,
     pipe: Pipeline,
@@ -61,6 +131,60 @@ impl RedisPipeline {
         }
     }
 
+    /*
+        切换逻辑库：SELECT 会影响 pipeline 中之后的所有命令，请作为第一个命令调用
+        注意：SELECT 改变的是连接状态，连接归还连接池后依然保持，后续使用者可能在错误的库上执行，
+        使用后应在末尾再 select 回默认库，或以 reset_connection 结尾
+        let result = state.redis.pipeline().await?
+            .select(1)
+            .get("key_in_db1")
+            .select(0)
+            .execute()
+            .await?;
+    */
+    pub fn select(mut self, db: u8) -> Self {
+        self.pipe.cmd("SELECT").arg(db);
+        self
+    }
+
+    /*
+        RESET（Redis 6.2+）：清除 SELECT、WATCH、MULTI、订阅、CLIENT SETNAME 等连接状态，回到默认库
+        连接来自共享的 r2d2 连接池，用过 select/watch/订阅等有状态命令的调用方，应在归还连接前以 reset_connection 结尾，
+        否则下一个拿到该连接的请求会继承这些状态
+        let result = state.redis.pipeline().await?
+            .select(1)
+            .get("key_in_db1")
+            .reset_connection()
+            .execute()
+            .await?;
+    */
+    pub fn reset_connection(mut self) -> Self {
+        self.pipe.cmd("RESET");
+        self
+    }
+
+    // 给连接命名，便于在 CLIENT LIST 中定位来源；RESET 会清除该名称
+    pub fn client_setname(mut self, name: &str) -> Self {
+        self.pipe.cmd("CLIENT").arg("SETNAME").arg(name);
+        self
+    }
+
+    // 把 key 移动到另一个逻辑库，目标库已存在同名 key 时不移动（返回 0）
+    pub fn move_key(mut self, key: &str, db: u8) -> Self {
+        self.pipe.cmd("MOVE").arg(key).arg(db);
+        self
+    }
+
+    /*
+        等待写入被 num_replicas 个副本确认，timeout_ms 为 0 时一直等待，结果用 get_last_i64 取已确认的副本数
+        .set("order:1", "paid")
+        .wait(1, 500)
+    */
+    pub fn wait(mut self, num_replicas: usize, timeout_ms: u64) -> Self {
+        self.pipe.cmd("WAIT").arg(num_replicas).arg(timeout_ms);
+        self
+    }
+
     // 基础 Redis 操作
     pub fn set<T: ToString>(mut self, key: &str, value: T) -> Self {
         self.pipe.cmd("SET").arg(key).arg(value.to_string());
@@ -87,6 +211,12 @@ impl RedisPipeline {
         self
     }
 
+    // 读取并删除（Redis 6.2+）
+    pub fn getdel(mut self, key: &str) -> Self {
+        self.pipe.cmd("GETDEL").arg(key);
+        self
+    }
+
     pub fn get_i64(mut self, key: &str) -> Self {
         self.pipe.cmd("GET").arg(key);
         self
@@ -132,6 +262,24 @@ impl RedisPipeline {
         self
     }
 
+    // 迁移操作：DUMP 的结果是二进制，用 get_last_bytes 取出
+    /*
+        let payload = source.pipeline().await?.dump("key1").execute().await?.get_last_bytes()?;
+        target.pipeline().await?.restore("key1", 0, &payload, true).execute().await?;
+    */
+    pub fn dump(mut self, key: &str) -> Self {
+        self.pipe.cmd("DUMP").arg(key);
+        self
+    }
+
+    pub fn restore(mut self, key: &str, ttl_ms: u64, payload: &[u8], replace: bool) -> Self {
+        self.pipe.cmd("RESTORE").arg(key).arg(ttl_ms).arg(payload);
+        if replace {
+            self.pipe.arg("REPLACE");
+        }
+        self
+    }
+
     // List 操作
     pub fn rpush_multiple(mut self, key: &str, values: &[String]) -> Self {
         self.pipe.cmd("RPUSH").arg(key).arg(values);
@@ -143,6 +291,39 @@ impl RedisPipeline {
         self
     }
 
+    // 可靠队列：原子地把元素从 src 移到 dst（处理中列表），崩溃后可从 dst 恢复
+    /*
+        let job = state.redis.pipeline().await?
+            .lmove("queue:pending", "queue:processing", Side::Right, Side::Left)
+            .execute()
+            .await?
+            .get_last_string()?;
+    */
+    pub fn lmove(mut self, src: &str, dst: &str, src_side: Side, dst_side: Side) -> Self {
+        self.pipe.cmd("LMOVE").arg(src).arg(dst).arg(src_side.as_str()).arg(dst_side.as_str());
+        self
+    }
+
+    // 旧版本 Redis（< 6.2）使用，等价于 lmove(src, dst, Side::Right, Side::Left)
+    pub fn rpoplpush(mut self, src: &str, dst: &str) -> Self {
+        self.pipe.cmd("RPOPLPUSH").arg(src).arg(dst);
+        self
+    }
+
+    // 多 key 弹出（Redis 7.0+）：从第一个非空的 key 弹出，结果用 get_last_mpop 解析
+    /*
+        let popped = state.redis.pipeline().await?
+            .lmpop(&["queue:high", "queue:normal", "queue:low"], Side::Left, 10)
+            .execute()
+            .await?
+            .get_last_mpop()?;
+        if let Some((queue, jobs)) = popped { ... }
+    */
+    pub fn lmpop(mut self, keys: &[&str], from: Side, count: usize) -> Self {
+        self.pipe.cmd("LMPOP").arg(keys.len()).arg(keys).arg(from.as_str()).arg("COUNT").arg(count);
+        self
+    }
+
     // Set 操作
     pub fn sadd_multiple(mut self, key: &str, values: &[String]) -> Self {
         self.pipe.cmd("SADD").arg(key).arg(values);
@@ -154,6 +335,110 @@ impl RedisPipeline {
         self
     }
 
+    // 只返回交集的大小（Redis 7.0+），limit 为 Some(n) 时数到 n 即停止
+    pub fn sintercard(mut self, keys: &[&str], limit: Option<usize>) -> Self {
+        self.pipe.cmd("SINTERCARD").arg(keys.len()).arg(keys);
+        if let Some(limit) = limit {
+            self.pipe.arg("LIMIT").arg(limit);
+        }
+        self
+    }
+
+    // Sorted Set 操作
+    /*
+        排行榜只保留前 N 名（按分数从高到低）：
+        state.redis.pipeline().await?
+            .zremrangebyrank("leaderboard", 0, -(n as isize) - 1)
+            .zcard("leaderboard")
+            .execute()
+            .await?;
+    */
+    pub fn zcard(mut self, key: &str) -> Self {
+        self.pipe.cmd("ZCARD").arg(key);
+        self
+    }
+
+    pub fn zcount<T: ToString>(mut self, key: &str, min: T, max: T) -> Self {
+        self.pipe.cmd("ZCOUNT").arg(key).arg(min.to_string()).arg(max.to_string());
+        self
+    }
+
+    pub fn zremrangebyrank(mut self, key: &str, start: isize, stop: isize) -> Self {
+        self.pipe.cmd("ZREMRANGEBYRANK").arg(key).arg(start).arg(stop);
+        self
+    }
+
+    pub fn zmpop(mut self, keys: &[&str], from: MinMax, count: usize) -> Self {
+        self.pipe.cmd("ZMPOP").arg(keys.len()).arg(keys).arg(from.as_str()).arg("COUNT").arg(count);
+        self
+    }
+
+    /*
+        // 分数从高到低取前 10 名及分数
+        .zrange_with("leaderboard", "+inf", "-inf", ZRangeOpts {
+            by: ZRangeBy::ByScore, rev: true, limit: Some((0, 10)), with_scores: true,
+        })
+        // 字典序范围
+        .zrange_with("names", "[a", "(c", ZRangeOpts { by: ZRangeBy::ByLex, ..Default::default() })
+    */
+    pub fn zrange_with<S: ToString>(mut self, key: &str, start: S, stop: S, opts: ZRangeOpts) -> Self {
+        self.pipe.cmd("ZRANGE").arg(key).arg(start.to_string()).arg(stop.to_string());
+        match opts.by {
+            ZRangeBy::Index => {}
+            ZRangeBy::ByScore => { self.pipe.arg("BYSCORE"); }
+            ZRangeBy::ByLex => { self.pipe.arg("BYLEX"); }
+        }
+        if opts.rev {
+            self.pipe.arg("REV");
+        }
+        if let Some((offset, count)) = opts.limit {
+            self.pipe.arg("LIMIT").arg(offset).arg(count);
+        }
+        if opts.with_scores {
+            self.pipe.arg("WITHSCORES");
+        }
+        self
+    }
+
+    /*
+        // 按 user:*:score 排序并同时取出元素本身和 user:*:name 两列
+        .sort("online_users", SortOpts {
+            by: Some("user:*:score".to_string()),
+            get: vec!["#".to_string(), "user:*:name".to_string()],
+            limit: Some((0, 20)),
+            desc: true,
+            ..Default::default()
+        })
+        let rows = results.get_last_sorted(2)?;
+    */
+    pub fn sort(mut self, key: &str, opts: SortOpts) -> Self {
+        self.pipe.cmd("SORT").arg(key);
+        if let Some(by) = &opts.by {
+            self.pipe.arg("BY").arg(by);
+        }
+        if let Some((offset, count)) = opts.limit {
+            self.pipe.arg("LIMIT").arg(offset).arg(count);
+        }
+        for pattern in &opts.get {
+            self.pipe.arg("GET").arg(pattern);
+        }
+        if opts.desc {
+            self.pipe.arg("DESC");
+        }
+        if opts.alpha {
+            self.pipe.arg("ALPHA");
+        }
+        self
+    }
+
+    pub fn zintercard(mut self, keys: &[&str], limit: Option<usize>) -> Self {
+        self.pipe.cmd("ZINTERCARD").arg(keys.len()).arg(keys);
+        if let Some(limit) = limit {
+            self.pipe.arg("LIMIT").arg(limit);
+        }
+        self
+    }
+
     // Hash 操作
     pub fn hset(mut self, key: &str, field: &str, value: &str) -> Self {
         self.pipe.cmd("HSET").arg(key).arg(field).arg(value);
@@ -187,6 +472,30 @@ impl RedisPipeline {
         self
     }
 
+    // Hash 字段级 TTL（Redis 7.4+），回复为每个字段的状态码数组，用 get_last_vec_i64 解析
+    /*
+        let codes = state.redis.pipeline().await?
+            .hexpire("session:1", 300, &["otp", "csrf"])
+            .execute()
+            .await?
+            .get_last_vec_i64()?;
+        // 1: 已设置过期，-2: 字段不存在
+    */
+    pub fn hexpire(mut self, key: &str, seconds: i64, fields: &[&str]) -> Self {
+        self.pipe.cmd("HEXPIRE").arg(key).arg(seconds).arg("FIELDS").arg(fields.len()).arg(fields);
+        self
+    }
+
+    pub fn httl(mut self, key: &str, fields: &[&str]) -> Self {
+        self.pipe.cmd("HTTL").arg(key).arg("FIELDS").arg(fields.len()).arg(fields);
+        self
+    }
+
+    pub fn hpersist(mut self, key: &str, fields: &[&str]) -> Self {
+        self.pipe.cmd("HPERSIST").arg(key).arg("FIELDS").arg(fields.len()).arg(fields);
+        self
+    }
+
     // Pattern 操作
     pub fn get_keys_by_pattern(mut self, pattern: &str) -> Self {
         self.pipe.cmd("KEYS").arg(pattern);
@@ -209,6 +518,12 @@ impl RedisPipeline {
             .map_err(|e| MyError::RedisError(e.to_string()))
     }
 
+    // 执行pipeline并按回复顺序映射为结构体
+    pub async fn execute_into<S: FromPipeline>(self) -> Result<S, MyError> {
+        let values = self.execute().await?;
+        S::from_pipeline(values)
+    }
+
     // 执行pipeline并获取单个结果
     pub async fn execute_single<T: FromRedisValue>(mut self) -> Result<T, MyError> {
         let mut results: Vec<T> = self.pipe.query(&mut *self.conn)
@@ -218,6 +533,106 @@ impl RedisPipeline {
             .ok_or_else(|| MyError::RedisError("No result returned".to_string()))
     }
 
+    // 大集合/大Hash的游标遍历：SSCAN/HSCAN 是循环而非单条命令，直接在连接上执行，不进入pipeline
+    /*
+        let mut pipeline = state.redis.pipeline().await?;
+        let members = pipeline.sscan_iter("big_set", "*", 500).await?;
+        let fields = pipeline.hscan_iter("big_hash", "user:*", 500).await?;
+    */
+    fn scan_collect(&mut self, command: &str, key: &str, pattern: &str, count: usize) -> Result<Vec<String>, MyError> {
+        let mut cursor: u64 = 0;
+        let mut items = Vec::new();
+        loop {
+            let (next, batch): (u64, Vec<String>) = cmd(command)
+                .arg(key)
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(count)
+                .query(&mut *self.conn)
+                .map_err(|e| MyError::RedisError(e.to_string()))?;
+            items.extend(batch);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        Ok(items)
+    }
+
+    pub async fn sscan_iter(&mut self, key: &str, pattern: &str, count: usize) -> Result<Vec<String>, MyError> {
+        self.scan_collect("SSCAN", key, pattern, count)
+    }
+
+    pub async fn hscan_iter(&mut self, key: &str, pattern: &str, count: usize) -> Result<HashMap<String, String>, MyError> {
+        let flat = self.scan_collect("HSCAN", key, pattern, count)?;
+        let mut fields = HashMap::with_capacity(flat.len() / 2);
+        let mut iter = flat.into_iter();
+        while let (Some(field), Some(value)) = (iter.next(), iter.next()) {
+            fields.insert(field, value);
+        }
+        Ok(fields)
+    }
+
+    /*
+        直接使用 pipeline 持有的连接执行中间读取，用于 WATCH -> 读取 -> 判断 -> MULTI/EXEC 的乐观锁流程，
+        保证 WATCH、读取和事务都在同一个连接上
+        注意：闭包内是同步阻塞调用，会占用当前 async 线程，闭包里只做少量快速的读取
+        let mut pipeline = state.redis.pipeline().await?;
+        let balance: i64 = pipeline.with_connection(|conn| {
+            cmd("WATCH").arg("balance").query::<()>(conn)?;
+            cmd("GET").arg("balance").query(conn)
+        })?;
+        let result = pipeline
+            .multi()
+            .set_i64("balance", balance - 10)
+            .exec()
+            .execute()
+            .await?
+            .transaction_results()?;  // WATCH 的 key 被修改时 EXEC 返回 nil，这里返回错误
+    */
+    pub fn with_connection<R>(&mut self, f: impl FnOnce(&mut Connection) -> RedisResult<R>) -> Result<R, MyError> {
+        f(&mut *self.conn).map_err(|e| MyError::RedisError(e.to_string()))
+    }
+
+    // 一次性令牌（重置密码、魔法链接）：原子地读取并删除，不存在时返回 None
+    /*
+        let mut pipeline = state.redis.pipeline().await?;
+        match pipeline.consume(&format!("reset:{}", token)).await? {
+            Some(user_id) => {}  // 令牌有效，且已被删除
+            None => {}           // 令牌不存在或已被使用
+        }
+    */
+    pub async fn consume(&mut self, key: &str) -> Result<Option<String>, MyError> {
+        cmd("GETDEL").arg(key)
+            .query(&mut *self.conn)
+            .map_err(|e| MyError::RedisError(e.to_string()))
+    }
+
+    // 先 TYPE 探测再按类型读取，比直接读取多一次往返，适合缓存巡检等通用工具
+    pub async fn get_typed(&mut self, key: &str) -> Result<RedisValue, MyError> {
+        let kind: String = cmd("TYPE").arg(key)
+            .query(&mut *self.conn)
+            .map_err(|e| MyError::RedisError(e.to_string()))?;
+        Self::read_typed(&mut self.conn, key, &kind)
+    }
+
+    fn read_typed(conn: &mut PooledConnection<RedisConnectionManager>, key: &str, kind: &str) -> Result<RedisValue, MyError> {
+        let to_err = |e: r2d2_redis::redis::RedisError| MyError::RedisError(e.to_string());
+        let value = match kind {
+            "none" => RedisValue::None,
+            "string" => RedisValue::String(cmd("GET").arg(key).query(&mut **conn).map_err(to_err)?),
+            "list" => RedisValue::List(cmd("LRANGE").arg(key).arg(0).arg(-1).query(&mut **conn).map_err(to_err)?),
+            "set" => RedisValue::Set(cmd("SMEMBERS").arg(key).query(&mut **conn).map_err(to_err)?),
+            "zset" => RedisValue::ZSet(cmd("ZRANGE").arg(key).arg(0).arg(-1).arg("WITHSCORES")
+                .query(&mut **conn).map_err(to_err)?),
+            "hash" => RedisValue::Hash(cmd("HGETALL").arg(key).query(&mut **conn).map_err(to_err)?),
+            other => return Err(MyError::RedisError(format!("Unsupported type {} for key {}", other, key))),
+        };
+        Ok(value)
+    }
+
     // 原子性控制
     pub fn atomic(mut self) -> Self {
         self.pipe.atomic();
@@ -235,6 +650,22 @@ impl RedisPipeline {
         self
     }
 
+    // 条件组合：cond 为 true 时才应用闭包
+    /*
+        let result = state.redis.pipeline().await?
+            .set("k", "v")
+            .when(refresh, |p| p.set_expiry("k", 60))
+            .execute()
+            .await?;
+    */
+    pub fn when(self, cond: bool, f: impl FnOnce(Self) -> Self) -> Self {
+        if cond {
+            f(self)
+        } else {
+            self
+        }
+    }
+
     // 辅助方法
     pub fn cmd(mut self, cmd: &str) -> Self {
         self.pipe.cmd(cmd);
@@ -253,6 +684,15 @@ pub trait PipelineResultExt {
     fn get_last_i32(&self) -> Result<i32, MyError>;
     fn get_last_string(&self) -> Result<String, MyError>;
     fn get_last_bool(&self) -> Result<bool, MyError>;
+    fn get_last_bytes(&self) -> Result<Vec<u8>, MyError>;
+    fn get_last_vec_i64(&self) -> Result<Vec<i64>, MyError>;
+    fn get_last_mpop(&self) -> Result<Option<(String, Vec<String>)>, MyError>;
+    fn get_i64_at(&self, index: usize) -> Result<i64, MyError>;
+    fn get_string_at(&self, index: usize) -> Result<String, MyError>;
+    fn transaction_results(&self) -> Result<Vec<Value>, MyError>;
+    fn parse_all<T: FromRedisValue>(&self) -> Result<Vec<T>, MyError>;
+    fn parse_pairs<K: FromRedisValue, V: FromRedisValue>(&self) -> Result<Vec<(K, V)>, MyError>;
+    fn get_last_sorted(&self, columns: usize) -> Result<Vec<Vec<Option<String>>>, MyError>;
 }
 
 impl PipelineResultExt for Vec<Value> {
@@ -291,8 +731,215 @@ impl PipelineResultExt for Vec<Value> {
             })
             .ok_or_else(|| MyError::RedisError("Failed to get bool value".to_string()))
     }
+
+    fn get_last_bytes(&self) -> Result<Vec<u8>, MyError> {
+        self.last()
+            .and_then(|v| match v {
+                Value::Data(bytes) => Some(bytes.clone()),
+                _ => None
+            })
+            .ok_or_else(|| MyError::RedisError("Failed to get bytes value".to_string()))
+    }
+
+    // 同类型的pipeline：每个回复都转换为 T
+    fn parse_all<T: FromRedisValue>(&self) -> Result<Vec<T>, MyError> {
+        self.iter()
+            .enumerate()
+            .map(|(index, v)| T::from_redis_value(v)
+                .map_err(|e| MyError::RedisError(format!("Failed to parse reply {}: {}", index, e))))
+            .collect()
+    }
+
+    // 最后一个回复是扁平的 key/value 数组（HGETALL、ZRANGE WITHSCORES 等）
+    fn parse_pairs<K: FromRedisValue, V: FromRedisValue>(&self) -> Result<Vec<(K, V)>, MyError> {
+        let items = match self.last() {
+            Some(Value::Bulk(items)) if items.len() % 2 == 0 => items,
+            _ => return Err(MyError::RedisError("Failed to get key/value pairs".to_string())),
+        };
+        items.chunks(2)
+            .map(|pair| {
+                let key = K::from_redis_value(&pair[0]).map_err(|e| MyError::RedisError(e.to_string()))?;
+                let value = V::from_redis_value(&pair[1]).map_err(|e| MyError::RedisError(e.to_string()))?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    // SORT 的结果按 GET 模式数切成行，没有 GET 时 columns 传 1；GET 的外部 key 不存在时对应列为 None
+    fn get_last_sorted(&self, columns: usize) -> Result<Vec<Vec<Option<String>>>, MyError> {
+        let columns = columns.max(1);
+        let items = match self.last() {
+            Some(Value::Bulk(items)) if items.len() % columns == 0 => items,
+            _ => return Err(MyError::RedisError("Failed to get sorted rows".to_string())),
+        };
+        items.chunks(columns)
+            .map(|row| {
+                row.iter()
+                    .map(|item| Option::<String>::from_redis_value(item).map_err(|e| MyError::RedisError(e.to_string())))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn get_last_vec_i64(&self) -> Result<Vec<i64>, MyError> {
+        self.last()
+            .and_then(|v| match v {
+                Value::Bulk(items) => items.iter()
+                    .map(|item| match item {
+                        Value::Int(i) => Some(*i),
+                        _ => None
+                    })
+                    .collect(),
+                _ => None
+            })
+            .ok_or_else(|| MyError::RedisError("Failed to get i64 array value".to_string()))
+    }
+
+    // LMPOP 回复 [key, [elem, ...]]，ZMPOP 回复 [key, [[member, score], ...]]（只保留 member），都为空时回复 nil
+    fn get_last_mpop(&self) -> Result<Option<(String, Vec<String>)>, MyError> {
+        let to_string = |v: &Value| match v {
+            Value::Data(bytes) => String::from_utf8(bytes.clone()).ok(),
+            Value::Bulk(pair) => match pair.first() {
+                Some(Value::Data(bytes)) => String::from_utf8(bytes.clone()).ok(),
+                _ => None
+            },
+            _ => None
+        };
+        match self.last() {
+            Some(Value::Nil) => Ok(None),
+            Some(Value::Bulk(reply)) if reply.len() == 2 => {
+                let key = to_string(&reply[0]);
+                let elements = match &reply[1] {
+                    Value::Bulk(items) => items.iter().map(to_string).collect::<Option<Vec<String>>>(),
+                    _ => None
+                };
+                match (key, elements) {
+                    (Some(key), Some(elements)) => Ok(Some((key, elements))),
+                    _ => Err(MyError::RedisError("Failed to parse multi-pop reply".to_string()))
+                }
+            }
+            _ => Err(MyError::RedisError("Failed to get multi-pop value".to_string()))
+        }
+    }
+
+    fn get_i64_at(&self, index: usize) -> Result<i64, MyError> {
+        self.get(index)
+            .and_then(|v| match v {
+                Value::Int(i) => Some(*i),
+                _ => None
+            })
+            .ok_or_else(|| MyError::RedisError(format!("Failed to get i64 value at {}", index)))
+    }
+
+    fn get_string_at(&self, index: usize) -> Result<String, MyError> {
+        self.get(index)
+            .and_then(|v| match v {
+                Value::Data(bytes) => String::from_utf8(bytes.clone()).ok(),
+                Value::Status(s) => Some(s.clone()),
+                Value::Okay => Some("OK".to_string()),
+                _ => None
+            })
+            .ok_or_else(|| MyError::RedisError(format!("Failed to get string value at {}", index)))
+    }
+
+    /*
+        事务回复形如：OK(MULTI), QUEUED, QUEUED, [EXEC结果...]
+        这里只取出 EXEC 数组里的真实回复，多个事务时依次拼接
+        let results = state.redis.pipeline().await?
+            .multi()
+            .increment("counter")
+            .get("key1")
+            .exec()
+            .execute()
+            .await?
+            .transaction_results()?;
+        let count = results.get_i64_at(0)?;
+        let value = results.get_string_at(1)?;
+    */
+    fn transaction_results(&self) -> Result<Vec<Value>, MyError> {
+        let mut results = Vec::new();
+        let mut found = false;
+        let mut in_multi = false;
+        let mut queued = 0usize;
+
+        for value in self {
+            match value {
+                Value::Okay if !in_multi => {
+                    in_multi = true;
+                    queued = 0;
+                }
+                Value::Status(s) if in_multi && s == "QUEUED" => queued += 1,
+                // EXEC 的回复数量必须与 QUEUED 的命令数一致
+                Value::Bulk(items) if in_multi && items.len() == queued => {
+                    results.extend(items.iter().cloned());
+                    found = true;
+                    in_multi = false;
+                }
+                Value::Nil if in_multi && queued > 0 => {
+                    return Err(MyError::RedisError("Transaction aborted: EXEC returned nil".to_string()));
+                }
+                Value::Okay => queued = 0,
+                _ => in_multi = false,
+            }
+        }
+
+        if found {
+            Ok(results)
+        } else {
+            Err(MyError::RedisError("No EXEC reply found".to_string()))
+        }
+    }
+}
+
+// 固定结构的pipeline：按字段顺序依次消费回复
+/*
+    struct SetAndCount {
+        set_ok: bool,
+        value: String,
+        count: i64,
+    }
+    impl_from_pipeline!(SetAndCount { set_ok: bool, value: String, count: i64 });
+
+    let result: SetAndCount = state.redis.pipeline().await?
+        .set("key1", "value1")   // 回复 0 -> set_ok
+        .get("key1")             // 回复 1 -> value
+        .increment("counter")    // 回复 2 -> count
+        .execute_into()
+        .await?;
+*/
+pub trait FromPipeline: Sized {
+    fn from_pipeline(values: Vec<Value>) -> Result<Self, MyError>;
 }
 
+impl FromPipeline for Vec<Value> {
+    fn from_pipeline(values: Vec<Value>) -> Result<Self, MyError> {
+        Ok(values)
+    }
+}
+
+#[macro_export]
+macro_rules! impl_from_pipeline {
+    ($name:ident { $($field:ident : $ty:ty),* $(,)? }) => {
+        impl $crate::models::redis_pipeline::FromPipeline for $name {
+            fn from_pipeline(
+                values: Vec<r2d2_redis::redis::Value>,
+            ) -> Result<Self, $crate::models::redis::MyError> {
+                let mut replies = values.into_iter();
+                Ok(Self {
+                    $($field: {
+                        let value = replies.next().ok_or_else(|| {
+                            $crate::models::redis::MyError::RedisError(
+                                format!("Missing reply for field {}", stringify!($field)))
+                        })?;
+                        <$ty as r2d2_redis::redis::FromRedisValue>::from_redis_value(&value)
+                            .map_err(|e| $crate::models::redis::MyError::RedisError(
+                                format!("Failed to parse field {}: {}", stringify!($field), e)))?
+                    },)*
+                })
+            }
+        }
+    };
+}
 
 
 #[cfg(test)]
@@ -356,4 +1003,78 @@ mod tests {
             .await;
         assert!(result.is_ok());
     }
-}
\ No newline at end of file
+
+    struct SetAndCount {
+        set_ok: bool,
+        value: String,
+        count: i64,
+    }
+    crate::impl_from_pipeline!(SetAndCount { set_ok: bool, value: String, count: i64 });
+
+    #[test]
+    fn test_from_pipeline_maps_replies_in_order() {
+        let replies = vec![Value::Okay, Value::Data(b"value1".to_vec()), Value::Int(3)];
+        let result = SetAndCount::from_pipeline(replies).unwrap();
+        assert!(result.set_ok);
+        assert_eq!(result.value, "value1");
+        assert_eq!(result.count, 3);
+
+        assert!(SetAndCount::from_pipeline(vec![Value::Okay]).is_err());
+    }
+
+    #[test]
+    fn test_transaction_results_unwraps_exec_reply() {
+        let replies = vec![
+            Value::Okay,
+            Value::Status("QUEUED".to_string()),
+            Value::Status("QUEUED".to_string()),
+            Value::Bulk(vec![Value::Int(5), Value::Data(b"value1".to_vec())]),
+        ];
+        let results = replies.transaction_results().unwrap();
+        assert_eq!(results.get_i64_at(0).unwrap(), 5);
+        assert_eq!(results.get_string_at(1).unwrap(), "value1");
+
+        let aborted = vec![Value::Okay, Value::Status("QUEUED".to_string()), Value::Nil];
+        assert!(aborted.transaction_results().is_err());
+    }
+
+    #[test]
+    fn test_get_last_mpop_parses_reply() {
+        let replies = vec![Value::Bulk(vec![
+            Value::Data(b"queue:high".to_vec()),
+            Value::Bulk(vec![Value::Data(b"job1".to_vec()), Value::Data(b"job2".to_vec())]),
+        ])];
+        let (key, jobs) = replies.get_last_mpop().unwrap().unwrap();
+        assert_eq!(key, "queue:high");
+        assert_eq!(jobs, vec!["job1".to_string(), "job2".to_string()]);
+
+        assert_eq!(vec![Value::Nil].get_last_mpop().unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_all_and_pairs() {
+        let replies = vec![Value::Int(1), Value::Data(b"2".to_vec())];
+        assert_eq!(replies.parse_all::<i64>().unwrap(), vec![1, 2]);
+
+        let replies = vec![Value::Bulk(vec![
+            Value::Data(b"alice".to_vec()), Value::Data(b"10".to_vec()),
+            Value::Data(b"bob".to_vec()), Value::Data(b"7".to_vec()),
+        ])];
+        let pairs = replies.parse_pairs::<String, i64>().unwrap();
+        assert_eq!(pairs, vec![("alice".to_string(), 10), ("bob".to_string(), 7)]);
+    }
+
+    #[test]
+    fn test_get_last_sorted() {
+        let replies = vec![Value::Bulk(vec![
+            Value::Data(b"1".to_vec()), Value::Data(b"alice".to_vec()),
+            Value::Data(b"2".to_vec()), Value::Nil,
+        ])];
+        let rows = replies.get_last_sorted(2).unwrap();
+        assert_eq!(rows, vec![
+            vec![Some("1".to_string()), Some("alice".to_string())],
+            vec![Some("2".to_string()), None],
+        ]);
+        assert!(replies.get_last_sorted(3).is_err());
+    }
+}